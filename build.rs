@@ -0,0 +1,19 @@
+//! Records the git commit frogbot is being built from, so `!version` can report it.
+
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+
+    // Builds from a source tarball won't have a commit, which is fine
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=FROGBOT_GIT_COMMIT={}", commit.trim());
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,129 @@
+//! # The Cache Module
+//!
+//! This module has the small in-memory cache that embeds, edits and `!weather` keep recent
+//! results in.
+
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+/// A map that holds at most a fixed number of entries, each remembering when it was added.
+///
+/// Once it's full, adding a new key drops the oldest entry to make room.
+pub struct TtlCache<K, V> {
+    /// The cached values, along with when each one was added
+    entries: HashMap<K, (Instant, V)>,
+    /// The most entries kept at once
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone, V> TtlCache<K, V> {
+    /// Creates an empty cache that holds at most `capacity` entries.
+    pub fn new(capacity: usize) -> TtlCache<K, V> {
+        TtlCache {
+            entries: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Gets the value for `key`, if it was added less than `ttl` ago.
+    pub fn get<Q>(&self, key: &Q, ttl: Duration) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries
+            .get(key)
+            .filter(|(added, _)| added.elapsed() < ttl)
+            .map(|(_, value)| value)
+    }
+
+    /// Stores `value` for `key`, forgetting whatever is older than `ttl` first.
+    pub fn insert(&mut self, key: K, value: V, ttl: Duration) {
+        self.entries.retain(|_, (added, _)| added.elapsed() < ttl);
+        self.make_room_for(&key);
+        self.entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Gets the value for `key`, adding the one from `default` if there isn't one yet.
+    ///
+    /// Entries added this way never expire, they only get dropped when the cache is full.
+    pub fn get_or_insert_with(&mut self, key: K, default: impl FnOnce() -> V) -> &mut V {
+        self.make_room_for(&key);
+        let (_, value) = self
+            .entries
+            .entry(key)
+            .or_insert_with(|| (Instant::now(), default()));
+        value
+    }
+
+    /// Drops the oldest entry if the cache is full and `key` would be a new one.
+    fn make_room_for(&mut self, key: &K) {
+        if self.entries.len() < self.capacity || self.entries.contains_key(key) {
+            return;
+        }
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, (added, _))| *added)
+            .map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn gets_fresh_entries() {
+        let mut cache = TtlCache::new(2);
+        cache.insert("a".to_string(), 1, TTL);
+        assert_eq!(cache.get("a", TTL), Some(&1));
+        assert_eq!(cache.get("b", TTL), None);
+    }
+
+    #[test]
+    fn skips_expired_entries() {
+        let mut cache = TtlCache::new(2);
+        cache.insert("a".to_string(), 1, TTL);
+        assert_eq!(cache.get("a", Duration::ZERO), None);
+    }
+
+    #[test]
+    fn drops_the_oldest_entry_when_full() {
+        let mut cache = TtlCache::new(2);
+        cache.insert("a".to_string(), 1, TTL);
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert("b".to_string(), 2, TTL);
+        cache.insert("c".to_string(), 3, TTL);
+        assert_eq!(cache.get("a", TTL), None);
+        assert_eq!(cache.get("b", TTL), Some(&2));
+        assert_eq!(cache.get("c", TTL), Some(&3));
+    }
+
+    #[test]
+    fn replacing_a_key_keeps_the_others() {
+        let mut cache = TtlCache::new(2);
+        cache.insert("a".to_string(), 1, TTL);
+        cache.insert("b".to_string(), 2, TTL);
+        cache.insert("b".to_string(), 3, TTL);
+        assert_eq!(cache.get("a", TTL), Some(&1));
+        assert_eq!(cache.get("b", TTL), Some(&3));
+    }
+
+    #[test]
+    fn get_or_insert_with_keeps_existing_values() {
+        let mut cache = TtlCache::new(2);
+        cache.get_or_insert_with("a".to_string(), Vec::new).push(1);
+        cache.get_or_insert_with("a".to_string(), Vec::new).push(2);
+        assert_eq!(cache.get("a", TTL), Some(&vec![1, 2]));
+    }
+}
@@ -0,0 +1,1581 @@
+//! # The Commands Module
+//!
+//! This module controls the commands frogbot responds to.
+
+use crate::{
+    embeds::{
+        self, get_embed, upload_thumbnail, EmbedSettings, MetadataFetcher, EMBED_SETTINGS_FILE,
+    },
+    metrics, reminders, retry_rate_limited, send_welcome_message, store, weather,
+    wiki::{self, WikiSummary},
+    BotState, Config, Status,
+};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use matrix_sdk::{
+    event_handler::Ctx,
+    room::{Joined, Room},
+    ruma::{
+        api::client::relations::get_relating_events_with_rel_type_and_event_type,
+        events::{
+            reaction::{self, ReactionEventContent},
+            relation::RelationType,
+            room::message::{
+                MessageType, OriginalRoomMessageEvent, OriginalSyncRoomMessageEvent, Relation,
+                RoomMessageEventContent,
+            },
+        },
+        MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomOrAliasId, UserId,
+    },
+    Client,
+};
+use rand::Rng;
+use serde::Deserialize;
+use url::Url;
+
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The future returned by a command's handler function.
+type CommandFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+/// A command frogbot responds to.
+struct Command {
+    /// The name the command is invoked with, without the prefix (e.g. "ping")
+    name: &'static str,
+    /// What the command takes after its name, for usage messages (e.g. "<room id or alias>")
+    usage: &'static str,
+    /// A one line description of what the command does, for `!help`
+    description: &'static str,
+    /// Whether only the admins listed in the [`Config`] can use the command
+    admin_only: bool,
+    /// Whether the command fetches things from the web or sends several messages, so it runs in
+    /// the background instead of holding up the sync loop
+    background: bool,
+    /// The function that runs the command
+    handler: for<'a> fn(&'a CommandContext) -> CommandFuture<'a>,
+}
+
+/// Every command frogbot knows about.
+///
+/// Adding a command to this list is all it takes for the dispatcher to route to it.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "[command]",
+        description: "Lists the commands, or shows how to use one of them",
+        admin_only: false,
+        background: false,
+        handler: help,
+    },
+    Command {
+        name: "ping",
+        usage: "",
+        description: "Checks that frogbot is alive",
+        admin_only: false,
+        background: false,
+        handler: ping,
+    },
+    Command {
+        name: "version",
+        usage: "",
+        description: "Shows which version of frogbot is running",
+        admin_only: false,
+        background: false,
+        handler: version,
+    },
+    Command {
+        name: "poll",
+        usage: "\"question\" option | option | ...",
+        description: "Starts a poll that people vote on by reacting",
+        admin_only: false,
+        background: true,
+        handler: poll,
+    },
+    Command {
+        name: "pollresult",
+        usage: "",
+        description: "Counts the votes on the poll being replied to, or the latest one",
+        admin_only: false,
+        background: true,
+        handler: poll_result,
+    },
+    Command {
+        name: "roll",
+        usage: "<dice, e.g. 2d6+1>",
+        description: "Rolls some dice",
+        admin_only: false,
+        background: false,
+        handler: roll,
+    },
+    Command {
+        name: "remindme",
+        usage: "<10s|5m|2h|1d> <message>",
+        description: "Reminds you about something later",
+        admin_only: false,
+        background: false,
+        handler: remindme,
+    },
+    Command {
+        name: "weather",
+        usage: "<location>",
+        description: "Shows the current weather somewhere",
+        admin_only: false,
+        background: true,
+        handler: weather,
+    },
+    Command {
+        name: "wiki",
+        usage: "<article>",
+        description: "Shows the summary of a Wikipedia article",
+        admin_only: false,
+        background: true,
+        handler: wiki,
+    },
+    Command {
+        name: "uptime",
+        usage: "",
+        description: "Shows how long frogbot has been running",
+        admin_only: false,
+        background: false,
+        handler: uptime,
+    },
+    Command {
+        name: "leave",
+        usage: "",
+        description: "Makes frogbot leave this room",
+        admin_only: true,
+        background: false,
+        handler: leave,
+    },
+    Command {
+        name: "rooms",
+        usage: "",
+        description: "Lists the rooms frogbot is in",
+        admin_only: true,
+        background: false,
+        handler: rooms,
+    },
+    Command {
+        name: "join",
+        usage: "<room id or alias>",
+        description: "Makes frogbot join a room",
+        admin_only: true,
+        background: false,
+        handler: join,
+    },
+    Command {
+        name: "announce",
+        usage: "<message>",
+        description: "Sends a message to every room frogbot is in",
+        admin_only: true,
+        background: false,
+        handler: announce,
+    },
+    Command {
+        name: "embed",
+        usage: "<url>",
+        description: "Shows the embed for a link, even where embeds are turned off",
+        admin_only: false,
+        background: true,
+        handler: embed,
+    },
+    Command {
+        name: "embeds",
+        usage: "on|off|optout|optin",
+        description: "Turns embeds on or off in this room, or for your own links",
+        // Anyone can opt themselves out, turning embeds on or off checks for admins itself
+        admin_only: false,
+        background: false,
+        handler: embeds,
+    },
+];
+
+lazy_static! {
+    /// When each user last ran each command, used to enforce the command cooldown
+    static ref LAST_INVOKED: Mutex<HashMap<(OwnedUserId, &'static str), Instant>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Checks if `user_id` ran `command` less than `cooldown` ago, recording this run if not.
+fn is_on_cooldown(user_id: &UserId, command: &'static str, cooldown: Duration) -> bool {
+    let mut last_invoked = LAST_INVOKED.lock().unwrap();
+    let now = Instant::now();
+    // Forget anything that's off cooldown, so the map doesn't grow forever
+    last_invoked.retain(|_, invoked| now.duration_since(*invoked) < cooldown);
+
+    let key = (user_id.to_owned(), command);
+    if last_invoked.contains_key(&key) {
+        return true;
+    }
+    last_invoked.insert(key, now);
+    false
+}
+
+/// The reply sent when someone who isn't an admin tries to use an admin-only command.
+const ADMIN_ONLY_MESSAGE: &str = "Sorry, only frogbot's admins can use that command.";
+
+/// The reply sent when a command that runs in the background can't be started yet.
+const BUSY_MESSAGE: &str = "Sorry, frogbot is busy right now, try again in a bit.";
+
+/// Everything a command needs to know about the message that invoked it.
+struct CommandContext {
+    /// The message that invoked the command
+    event: OriginalRoomMessageEvent,
+    /// The room the command was sent in
+    room: Joined,
+    /// frogbot's own client
+    client: Client,
+    /// frogbot's configuration
+    config: Arc<Config>,
+    /// The embed settings that can be changed at runtime
+    embed_settings: Arc<Mutex<EmbedSettings>>,
+    /// What frogbot knows about itself while running
+    status: Arc<Status>,
+    /// What pages for embeds are fetched with
+    fetcher: Arc<dyn MetadataFetcher>,
+    /// The HTTP client used for embeds that don't come from the page itself
+    reqwest_client: reqwest::Client,
+    /// The command being run
+    command: &'static Command,
+    /// Everything after the command name
+    args: String,
+}
+
+impl CommandContext {
+    /// Checks if the command was sent by one of the admins listed in the [`Config`].
+    fn sent_by_admin(&self) -> bool {
+        is_admin(&self.event.sender, &self.config)
+    }
+
+    /// Replies to the message that invoked the command.
+    async fn reply(&self, message: &str) -> anyhow::Result<()> {
+        let content = RoomMessageEventContent::text_plain(message).make_reply_to(&self.event);
+        retry_rate_limited(|| self.room.send(content.clone(), None)).await?;
+        Ok(())
+    }
+
+    /// Replies with how to use the command, for when it was given the wrong arguments.
+    async fn reply_usage(&self) -> anyhow::Result<()> {
+        let usage = format!(
+            "Usage: {}",
+            format_usage(self.command, &self.config.command_prefix)
+        );
+        self.reply(&usage).await
+    }
+}
+
+/// Checks if `user_id` is one of the admins listed in the [`Config`].
+fn is_admin(user_id: &UserId, config: &Config) -> bool {
+    config.admins.iter().any(|admin| admin == user_id)
+}
+
+/// Checks if `user_id` is allowed to run `command`.
+fn can_run(command: &Command, user_id: &UserId, config: &Config) -> bool {
+    !command.admin_only || is_admin(user_id, config)
+}
+
+/// Formats how to use `command`, e.g. `!join <room id or alias>`.
+fn format_usage(command: &Command, prefix: &str) -> String {
+    format!("{}{} {}", prefix, command.name, command.usage)
+        .trim_end()
+        .to_string()
+}
+
+/// Lists every command with its description, or shows the usage of a single command with
+/// `!help <command>`.
+///
+/// This is built from [`COMMANDS`], so new commands show up here without any extra work.
+fn help(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let help = help_message(&ctx.args, &ctx.config.command_prefix);
+        ctx.reply(&help).await
+    })
+}
+
+/// Builds the reply to `!help`, with `args` being everything after the command name.
+fn help_message(args: &str, prefix: &str) -> String {
+    let admin_note = |command: &Command| {
+        if command.admin_only {
+            " (admins only)"
+        } else {
+            ""
+        }
+    };
+
+    if args.is_empty() {
+        let lines: Vec<String> = COMMANDS
+            .iter()
+            .map(|command| {
+                format!(
+                    "{}{} - {}{}",
+                    prefix,
+                    command.name,
+                    command.description,
+                    admin_note(command)
+                )
+            })
+            .collect();
+        return lines.join("\n");
+    }
+
+    let name = args.strip_prefix(prefix).unwrap_or(args);
+    match find_command(name) {
+        Some(command) => format!(
+            "{}\n{}{}",
+            format_usage(command, prefix),
+            command.description,
+            admin_note(command)
+        ),
+        None => format!("There's no command called '{}', try {}help", name, prefix),
+    }
+}
+
+/// Replies with "pong" and how long the message took to reach frogbot.
+fn ping(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let pong = pong_message(
+            ctx.event.origin_server_ts,
+            MilliSecondsSinceUnixEpoch::now(),
+        );
+        ctx.reply(&pong).await
+    })
+}
+
+/// Builds the reply to `!ping`, for a message sent at `sent` and handled at `now`.
+fn pong_message(sent: MilliSecondsSinceUnixEpoch, now: MilliSecondsSinceUnixEpoch) -> String {
+    // If the server's clock is ahead of ours the message can look like it came from the future
+    let latency = u64::from(now.get()).saturating_sub(u64::from(sent.get()));
+    format!("pong ({}ms)", latency)
+}
+
+/// Replies with the version of frogbot that's running, and the commit it was built from if known.
+fn version(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move { ctx.reply(&version_message()).await })
+}
+
+/// Builds the reply to `!version`.
+fn version_message() -> String {
+    match option_env!("FROGBOT_GIT_COMMIT") {
+        Some(commit) => format!("frogbot v{} ({})", env!("CARGO_PKG_VERSION"), commit),
+        None => format!("frogbot v{}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// The most dice `!roll` rolls at once.
+const MAX_DICE: u32 = 100;
+
+/// The most sides a die can have for `!roll`.
+const MAX_DIE_SIDES: u32 = 1000;
+
+/// The biggest number that can be added to or taken off a `!roll` total.
+const MAX_DICE_MODIFIER: i64 = 10_000;
+
+/// Dice to roll, in standard notation like `3d6+2`.
+#[derive(Debug, PartialEq, Eq)]
+struct Dice {
+    /// How many dice to roll
+    count: u32,
+    /// How many sides each die has
+    sides: u32,
+    /// A number added to the total, which can be negative
+    modifier: i64,
+}
+
+/// Parses dice notation like `1d20`, `d6`, `3d6+2` or `2d8-1`.
+fn parse_dice(notation: &str) -> Option<Dice> {
+    let notation = notation.trim().to_ascii_lowercase();
+    let (count, rest) = notation.split_once('d')?;
+    let (sides, modifier) = match rest.find(['+', '-']) {
+        Some(sign) => (&rest[..sign], rest[sign..].parse().ok()?),
+        None => (rest, 0),
+    };
+
+    let count = if count.is_empty() {
+        1
+    } else {
+        count.parse().ok()?
+    };
+    let sides = sides.parse().ok()?;
+    if !(1..=MAX_DICE).contains(&count)
+        || !(2..=MAX_DIE_SIDES).contains(&sides)
+        || !(-MAX_DICE_MODIFIER..=MAX_DICE_MODIFIER).contains(&modifier)
+    {
+        return None;
+    }
+    Some(Dice {
+        count,
+        sides,
+        modifier,
+    })
+}
+
+/// Rolls `dice`, returning each die and the total with the modifier added.
+///
+/// Returns `None` if the total doesn't fit in an `i64`, which [`parse_dice`]'s limits should
+/// already rule out.
+fn roll_dice(dice: &Dice, rng: &mut impl Rng) -> Option<(Vec<u32>, i64)> {
+    let rolls: Vec<u32> = (0..dice.count)
+        .map(|_| rng.gen_range(1..=dice.sides))
+        .collect();
+    let total = rolls.iter().try_fold(dice.modifier, |total, roll| {
+        total.checked_add(i64::from(*roll))
+    })?;
+    Some((rolls, total))
+}
+
+/// Rolls dice, e.g. `!roll 2d6+1`, and replies with each die and the total.
+fn roll(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let Some(dice) = parse_dice(&ctx.args) else {
+            return ctx.reply_usage().await;
+        };
+
+        let Some((rolls, total)) = roll_dice(&dice, &mut rand::thread_rng()) else {
+            return ctx.reply("🎲 That total is too big to count").await;
+        };
+        let rolls: Vec<String> = rolls.iter().map(u32::to_string).collect();
+        let reply = match dice.modifier {
+            0 => format!("🎲 [{}] = {}", rolls.join(", "), total),
+            modifier => format!("🎲 [{}] {:+} = {}", rolls.join(", "), modifier, total),
+        };
+        ctx.reply(&reply).await
+    })
+}
+
+/// Sets a reminder, e.g. `!remindme 30m take the pizza out`.
+fn remindme(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let (duration, message) = ctx
+            .args
+            .split_once(char::is_whitespace)
+            .unwrap_or((&ctx.args, ""));
+        let (Some(delay), false) = (
+            reminders::parse_duration(duration),
+            message.trim().is_empty(),
+        ) else {
+            return ctx.reply_usage().await;
+        };
+
+        reminders::add(
+            &ctx.config,
+            ctx.room.room_id(),
+            &ctx.event.sender,
+            message.trim(),
+            delay,
+        )?;
+        ctx.reply(&format!(
+            "Okay, I'll remind you in {}",
+            format_duration(delay)
+        ))
+        .await
+    })
+}
+
+/// Shows the current weather at a location, e.g. `!weather Paris`.
+fn weather(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        if ctx.args.is_empty() {
+            return ctx.reply_usage().await;
+        }
+
+        let provider = weather::provider(ctx.config.weather_provider, ctx.reqwest_client.clone());
+        match weather::current_weather(provider.as_ref(), &ctx.args).await {
+            Ok(Some(weather)) => ctx.reply(&weather.to_reply()).await,
+            Ok(None) => ctx.reply(&format!("Couldn't find {}", ctx.args)).await,
+            Err(e) => {
+                warn!("Failed to get the weather for: '{}': {}", ctx.args, e);
+                ctx.reply("Couldn't get the weather right now").await
+            }
+        }
+    })
+}
+
+/// Shows the summary of a Wikipedia article, e.g. `!wiki Frog`.
+fn wiki(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        if ctx.args.is_empty() {
+            return ctx.reply_usage().await;
+        }
+
+        match wiki::fetch_summary(&ctx.reqwest_client, &ctx.args).await {
+            Ok(WikiSummary::Article(mut embed)) => {
+                let url = embed.link.clone().unwrap_or_default();
+                upload_thumbnail(&mut embed, &url, &ctx.client, &*ctx.fetcher, &ctx.config).await;
+                let content = embed.to_message_content(&ctx.config, &ctx.event);
+                retry_rate_limited(|| ctx.room.send(content.clone(), None)).await?;
+                Ok(())
+            }
+            Ok(WikiSummary::Disambiguation { title, url }) => {
+                ctx.reply(&format!(
+                    "\"{}\" could mean a few different things, try being more specific: {}",
+                    title, url
+                ))
+                .await
+            }
+            Ok(WikiSummary::NotFound) => {
+                ctx.reply(&format!(
+                    "Wikipedia doesn't have an article on {}",
+                    ctx.args
+                ))
+                .await
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to get the Wikipedia summary for: '{}': {}",
+                    ctx.args, e
+                );
+                ctx.reply("Couldn't reach Wikipedia right now").await
+            }
+        }
+    })
+}
+
+/// Replies with how long frogbot has been running, and when it last synced.
+fn uptime(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let since_last_sync = ctx.status.last_sync().map(|last_sync| last_sync.elapsed());
+        ctx.reply(&uptime_message(
+            ctx.status.started.elapsed(),
+            since_last_sync,
+        ))
+        .await
+    })
+}
+
+/// Says how long frogbot has been running for, and how long ago it last synced if it has.
+fn uptime_message(uptime: Duration, since_last_sync: Option<Duration>) -> String {
+    let mut message = format!("Up for {}", format_duration(uptime));
+    if let Some(since_last_sync) = since_last_sync {
+        message.push_str(&format!(
+            ", last synced {} ago",
+            format_duration(since_last_sync)
+        ));
+    }
+    message
+}
+
+/// Formats a duration for people to read, e.g. "2d 3h 4m 5s".
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, secs) = (
+        secs / 86400,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+    if days > 0 {
+        format!("{}d {}h {}m {}s", days, hours, minutes, secs)
+    } else if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// The reactions people vote with in polls, which also limits how many options a poll can have.
+const POLL_EMOJIS: &[&str] = &["1️⃣", "2️⃣", "3️⃣", "4️⃣", "5️⃣", "6️⃣", "7️⃣", "8️⃣", "9️⃣", "🔟"];
+
+/// How many reactions to ask the homeserver for at a time when counting poll votes.
+const POLL_REACTION_PAGE_SIZE: u32 = 100;
+
+/// The most pages of reactions read when counting poll votes, so a huge poll can't keep
+/// `!pollresult` going forever.
+const MAX_POLL_REACTION_PAGES: usize = 50;
+
+/// A poll started with `!poll`.
+#[derive(Clone)]
+struct Poll {
+    /// What the poll is asking
+    question: String,
+    /// The options to vote for, in the same order as [`POLL_EMOJIS`]
+    options: Vec<String>,
+}
+
+lazy_static! {
+    /// The polls started since frogbot started, by the event ID of the poll message
+    static ref POLLS: Mutex<HashMap<OwnedEventId, Poll>> = Mutex::new(HashMap::new());
+    /// The latest poll started in each room
+    static ref LATEST_POLLS: Mutex<HashMap<OwnedRoomId, OwnedEventId>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Parses `"question" option | option | ...` into a [`Poll`].
+fn parse_poll(args: &str) -> Option<Poll> {
+    let (question, options) = args.strip_prefix('"')?.split_once('"')?;
+    let options: Vec<String> = options
+        .split('|')
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    if question.trim().is_empty() || !(2..=POLL_EMOJIS.len()).contains(&options.len()) {
+        return None;
+    }
+    Some(Poll {
+        question: question.trim().to_string(),
+        options,
+    })
+}
+
+/// Starts a poll, e.g. `!poll "Best frog?" Tree frog | Bullfrog | Poison dart frog`.
+///
+/// frogbot reacts to the poll with an emoji for each option, so voting is just clicking one.
+fn poll(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let Some(poll) = parse_poll(&ctx.args) else {
+            return ctx.reply_usage().await;
+        };
+
+        let mut message = format!("📊 {}", poll.question);
+        for (emoji, option) in POLL_EMOJIS.iter().zip(&poll.options) {
+            message.push_str(&format!("\n{} {}", emoji, option));
+        }
+        let content = RoomMessageEventContent::text_plain(message);
+        let poll_id = retry_rate_limited(|| ctx.room.send(content.clone(), None))
+            .await?
+            .event_id;
+
+        POLLS.lock().unwrap().insert(poll_id.clone(), poll.clone());
+        LATEST_POLLS
+            .lock()
+            .unwrap()
+            .insert(ctx.room.room_id().to_owned(), poll_id.clone());
+
+        for emoji in &POLL_EMOJIS[..poll.options.len()] {
+            let reaction = ReactionEventContent::new(reaction::Relation::new(
+                poll_id.clone(),
+                emoji.to_string(),
+            ));
+            retry_rate_limited(|| ctx.room.send(reaction.clone(), None)).await?;
+        }
+        Ok(())
+    })
+}
+
+/// The parts of a reaction event needed to count poll votes.
+#[derive(Deserialize)]
+struct Vote {
+    sender: OwnedUserId,
+    content: VoteContent,
+}
+
+/// The content of a reaction event.
+#[derive(Deserialize)]
+struct VoteContent {
+    #[serde(rename = "m.relates_to")]
+    relates_to: VoteRelation,
+}
+
+/// What a reaction event is reacting with.
+#[derive(Deserialize)]
+struct VoteRelation {
+    key: String,
+}
+
+/// Counts the votes on a poll, either the one the command replies to or the latest in the room.
+///
+/// Everyone gets one vote per option, and frogbot's own reactions don't count.
+fn poll_result(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let poll_id = match &ctx.event.content.relates_to {
+            Some(Relation::Reply { in_reply_to }) => Some(in_reply_to.event_id.clone()),
+            _ => LATEST_POLLS
+                .lock()
+                .unwrap()
+                .get(ctx.room.room_id())
+                .cloned(),
+        };
+        let poll = poll_id.and_then(|poll_id| {
+            let poll = POLLS.lock().unwrap().get(&poll_id).cloned()?;
+            Some((poll_id, poll))
+        });
+        let Some((poll_id, poll)) = poll else {
+            return ctx
+                .reply("Couldn't find that poll, it might be from before frogbot restarted")
+                .await;
+        };
+
+        // The homeserver hands reactions out a page at a time, so keep going until the last one
+        let mut reactions = Vec::new();
+        let mut from: Option<String> = None;
+        for _ in 0..MAX_POLL_REACTION_PAGES {
+            let mut request = get_relating_events_with_rel_type_and_event_type::v1::Request::new(
+                ctx.room.room_id(),
+                &poll_id,
+                RelationType::Annotation,
+                "m.reaction".into(),
+            );
+            request.from = from.as_deref();
+            request.limit = Some(POLL_REACTION_PAGE_SIZE.into());
+            let response = ctx.client.send(request, None).await?;
+            reactions.extend(response.chunk);
+            from = response.next_batch;
+            if from.is_none() {
+                break;
+            }
+        }
+
+        let own_user_id = ctx.client.user_id();
+        let mut voters: Vec<HashSet<OwnedUserId>> = vec![HashSet::new(); poll.options.len()];
+        for vote in reactions {
+            let Ok(vote) = vote.deserialize_as::<Vote>() else {
+                continue;
+            };
+            if Some(&*vote.sender) == own_user_id {
+                continue;
+            }
+            if let Some(option) = POLL_EMOJIS
+                .iter()
+                .position(|emoji| *emoji == vote.content.relates_to.key)
+                .filter(|option| *option < poll.options.len())
+            {
+                voters[option].insert(vote.sender);
+            }
+        }
+
+        let mut results = format!("📊 Results for: {}", poll.question);
+        for ((emoji, option), voters) in POLL_EMOJIS.iter().zip(&poll.options).zip(&voters) {
+            results.push_str(&format!("\n{} {} - {}", emoji, option, voters.len()));
+        }
+        ctx.reply(&results).await
+    })
+}
+
+/// Makes frogbot leave the room the command was sent in.
+fn leave(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        ctx.reply("Bye!").await?;
+        info!("Leaving room: '{}'", ctx.room.room_id());
+        ctx.room.leave().await?;
+        Ok(())
+    })
+}
+
+/// The most rooms `!rooms` lists, so the reply doesn't flood the room.
+const MAX_LISTED_ROOMS: usize = 50;
+
+/// Lists the rooms frogbot has joined, with their names.
+fn rooms(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let joined_rooms = ctx.client.joined_rooms();
+        let mut lines: Vec<String> = joined_rooms
+            .iter()
+            .take(MAX_LISTED_ROOMS)
+            .map(|room| match room.name() {
+                Some(name) => format!("{} ({})", room.room_id(), name),
+                None => room.room_id().to_string(),
+            })
+            .collect();
+        if joined_rooms.len() > MAX_LISTED_ROOMS {
+            lines.push(format!(
+                "...and {} more",
+                joined_rooms.len() - MAX_LISTED_ROOMS
+            ));
+        }
+
+        let reply = format!("In {} rooms:\n{}", joined_rooms.len(), lines.join("\n"));
+        ctx.reply(&reply).await
+    })
+}
+
+/// Makes frogbot join a room by its ID or alias, e.g. `!join #frogs:matrix.org`.
+///
+/// Rooms joined this way stay joined across restarts, since the homeserver remembers them.
+fn join(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let Ok(room_or_alias) = RoomOrAliasId::parse(ctx.args.as_str()) else {
+            return ctx.reply_usage().await;
+        };
+
+        info!("Joining room: '{}'", room_or_alias);
+        let join = || ctx.client.join_room_by_id_or_alias(&room_or_alias, &[]);
+        match retry_rate_limited(join).await {
+            Ok(response) => {
+                ctx.reply(&format!("Joined {}", room_or_alias)).await?;
+                send_welcome_message(&ctx.client, &response.room_id, &ctx.config).await
+            }
+            Err(e) => {
+                ctx.reply(&format!("Couldn't join {}: {}", room_or_alias, e))
+                    .await
+            }
+        }
+    })
+}
+
+/// How long `!announce` waits between rooms, on top of backing off when it's rate limited.
+const ANNOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Sends a message to every room frogbot is in, e.g. `!announce Restarting for updates soon`.
+///
+/// The rooms are sent to one at a time with a short pause in between, so a big announcement
+/// doesn't run into the homeserver's rate limits. That happens in the background, with a reply
+/// saying how many rooms it's going to straight away.
+fn announce(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        if ctx.args.is_empty() {
+            return ctx.reply_usage().await;
+        }
+
+        let content = RoomMessageEventContent::text_plain(format!("📢 {}", ctx.args));
+        let joined_rooms = ctx.client.joined_rooms();
+        let sender = ctx.event.sender.clone();
+        let room_count = joined_rooms.len();
+
+        // Going through every room takes a while, and the sync loop waits on command handlers, so
+        // the sending happens in the background
+        tokio::spawn(async move {
+            let mut failed = 0;
+            for (i, room) in joined_rooms.iter().enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(ANNOUNCE_DELAY).await;
+                }
+                if let Err(e) = retry_rate_limited(|| room.send(content.clone(), None)).await {
+                    warn!(
+                        "Failed to send announcement to room: '{}': {}",
+                        room.room_id(),
+                        e
+                    );
+                    failed += 1;
+                }
+            }
+
+            info!(
+                "Announcement sent by '{}' went to {} of {} rooms",
+                sender,
+                joined_rooms.len() - failed,
+                joined_rooms.len()
+            );
+        });
+
+        ctx.reply(&format!("Announcing in {} rooms", room_count))
+            .await
+    })
+}
+
+/// Replies with the embed for a single link, e.g. `!embed https://example.com`.
+///
+/// This works even in rooms where embeds are turned off, since someone asked for it.
+fn embed(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let url = match Url::parse(&ctx.args) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+            _ => return ctx.reply_usage().await,
+        };
+
+        match get_embed(&*ctx.fetcher, url.as_str(), &ctx.config).await {
+            Ok(Some(mut embed)) => {
+                upload_thumbnail(
+                    &mut embed,
+                    url.as_str(),
+                    &ctx.client,
+                    &*ctx.fetcher,
+                    &ctx.config,
+                )
+                .await;
+                let content = embed.to_message_content(&ctx.config, &ctx.event);
+                retry_rate_limited(|| ctx.room.send(content.clone(), None)).await?;
+                Ok(())
+            }
+            Ok(None) => {
+                ctx.reply("Couldn't find anything to embed for that link")
+                    .await
+            }
+            // The error can say what a hostname resolves to, which isn't for everyone in the room
+            Err(e) => {
+                debug!("Failed to embed '{}' for !embed: {}", url, e);
+                ctx.reply("Couldn't embed that link").await
+            }
+        }
+    })
+}
+
+/// Changes embed settings with `!embeds on|off` for the room, or `!embeds optout|optin` for
+/// the user who sent it.
+///
+/// Anyone can opt themselves out, but only admins can turn embeds on or off for a whole room.
+fn embeds(ctx: &CommandContext) -> CommandFuture<'_> {
+    Box::pin(async move {
+        let sender = ctx.event.sender.clone();
+        let room_id = ctx.room.room_id().to_owned();
+        let reply = match ctx.args.as_str() {
+            "on" | "off" if !ctx.sent_by_admin() => {
+                return ctx.reply(ADMIN_ONLY_MESSAGE).await;
+            }
+            "on" | "off" => {
+                let enabled = ctx.args == "on";
+                update_embed_settings(ctx, |embed_settings| {
+                    embed_settings.rooms.insert(room_id.clone(), enabled);
+                })?;
+                info!("Turned embeds {} in room: '{}'", ctx.args, room_id);
+                format!("Embeds are now {} in this room", ctx.args)
+            }
+            "optout" => {
+                update_embed_settings(ctx, |embed_settings| {
+                    embed_settings.opted_out_users.insert(sender.clone());
+                })?;
+                info!("User: '{}' opted out of embeds", sender);
+                "Your links won't get embeds anymore".to_string()
+            }
+            "optin" => {
+                update_embed_settings(ctx, |embed_settings| {
+                    embed_settings.opted_out_users.remove(&sender);
+                })?;
+                info!("User: '{}' opted back in to embeds", sender);
+                "Your links will get embeds again".to_string()
+            }
+            _ => return ctx.reply_usage().await,
+        };
+        ctx.reply(&reply).await
+    })
+}
+
+/// Changes the [`EmbedSettings`] and saves them to the store.
+fn update_embed_settings(
+    ctx: &CommandContext,
+    update: impl FnOnce(&mut EmbedSettings),
+) -> anyhow::Result<()> {
+    // Save while still holding the lock, so two changes can't save out of order
+    let mut embed_settings = ctx.embed_settings.lock().unwrap();
+    update(&mut embed_settings);
+    store::save(
+        ctx.config.store_path.as_deref(),
+        EMBED_SETTINGS_FILE,
+        &*embed_settings,
+    )
+}
+
+/// A command parsed out of a message.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invocation<'a> {
+    /// The name of the command, without the prefix
+    pub name: &'a str,
+    /// Everything after the command name, with surrounding whitespace trimmed
+    pub args: &'a str,
+}
+
+/// Parses a message into an [`Invocation`] if it starts with the command prefix.
+pub fn parse_command<'a>(message: &'a str, prefix: &str) -> Option<Invocation<'a>> {
+    let command = message.trim_start().strip_prefix(prefix)?;
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(Invocation {
+        name,
+        args: args.trim(),
+    })
+}
+
+/// Finds the command called `name` in [`COMMANDS`].
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Checks messages for commands and runs them
+pub async fn command_handler(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: Client,
+    Ctx(state): Ctx<BotState>,
+) {
+    let Room::Joined(room) = room else {
+        return;
+    };
+
+    // If the sender ID matches our client, ignore the message
+    // We don't want to reply to ourselves
+    let client_user_id = client.user_id().unwrap();
+    if event.sender == client_user_id {
+        return;
+    }
+
+    let config = state.config.get();
+    let BotState {
+        embed_settings,
+        fetcher,
+        reqwest_client,
+        status,
+        ..
+    } = state;
+    // Other bots sharing our prefix could otherwise end up running commands off each other
+    if config.is_ignored(&event.sender) {
+        return;
+    }
+
+    let MessageType::Text(text_content) = &event.content.msgtype else {
+        return;
+    };
+    let Some(invocation) = parse_command(&text_content.body, &config.command_prefix) else {
+        return;
+    };
+
+    // Other bots in the room might share our prefix, so stay quiet about commands we don't know
+    let Some(command) = find_command(invocation.name) else {
+        debug!("Ignoring unknown command: '{}'", invocation.name);
+        return;
+    };
+
+    // Don't let anyone flood the room by spamming a command
+    let cooldown = Duration::from_secs(config.command_cooldown_secs);
+    if is_on_cooldown(&event.sender, command.name, cooldown) {
+        debug!(
+            "Ignoring command: '{}' sent by '{}' during its cooldown",
+            command.name, event.sender
+        );
+        return;
+    }
+
+    info!(
+        "Running command: '{}' sent by '{}'",
+        command.name, event.sender
+    );
+    let ctx = CommandContext {
+        command,
+        args: invocation.args.to_string(),
+        event: event.clone().into_full_event(room.room_id().to_owned()),
+        room,
+        client,
+        config,
+        embed_settings,
+        status,
+        fetcher,
+        reqwest_client,
+    };
+
+    if !can_run(command, &ctx.event.sender, &ctx.config) {
+        warn!(
+            "Refusing admin command: '{}' sent by non-admin '{}'",
+            command.name, event.sender
+        );
+        if let Err(e) = ctx.reply(ADMIN_ONLY_MESSAGE).await {
+            warn!(
+                "Failed to send refusal for command: '{}': {}",
+                command.name, e
+            );
+        }
+        return;
+    }
+
+    metrics::COMMANDS_HANDLED.increment();
+    if !command.background {
+        run_command(&ctx).await;
+        return;
+    }
+
+    // Slow fetches would hold up every other event, so these share the task limit with embeds
+    let Some(permit) = embeds::embed_task_permit() else {
+        warn!(
+            "Too many embeds in progress, skipping command: '{}'",
+            command.name
+        );
+        if let Err(e) = ctx.reply(BUSY_MESSAGE).await {
+            warn!(
+                "Failed to send busy reply for command: '{}': {}",
+                command.name, e
+            );
+        }
+        return;
+    };
+    tokio::spawn(async move {
+        run_command(&ctx).await;
+        drop(permit);
+    });
+}
+
+/// Runs a command, logging it if it fails.
+async fn run_command(ctx: &CommandContext) {
+    if let Err(e) = (ctx.command.handler)(ctx).await {
+        warn!("Command: '{}' failed with error: {}", ctx.command.name, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message_event, FakeFetcher, FakeHomeserver};
+    use matrix_sdk::ruma::RoomId;
+
+    #[test]
+    fn prefixed_messages_are_commands() {
+        let invocation = parse_command("  !ping", "!").unwrap();
+        assert_eq!(
+            invocation,
+            Invocation {
+                name: "ping",
+                args: ""
+            }
+        );
+        assert_eq!(find_command(invocation.name).unwrap().name, "ping");
+
+        let invocation = parse_command("?join   #frogs:example.com ", "?").unwrap();
+        assert_eq!(invocation.name, "join");
+        assert_eq!(invocation.args, "#frogs:example.com");
+    }
+
+    #[test]
+    fn other_messages_are_not_commands() {
+        assert_eq!(parse_command("ping", "!"), None);
+        assert_eq!(parse_command("say !ping", "!"), None);
+        assert_eq!(parse_command("! ping", "!"), None);
+        assert!(find_command("frobnicate").is_none());
+    }
+
+    #[test]
+    fn ping_replies_with_the_latency() {
+        let sent = MilliSecondsSinceUnixEpoch(1_000_000u32.into());
+        let now = MilliSecondsSinceUnixEpoch(1_000_250u32.into());
+        assert_eq!(pong_message(sent, now), "pong (250ms)");
+    }
+
+    #[test]
+    fn ping_clamps_messages_from_the_future() {
+        let sent = MilliSecondsSinceUnixEpoch(1_000_500u32.into());
+        let now = MilliSecondsSinceUnixEpoch(1_000_000u32.into());
+        assert_eq!(pong_message(sent, now), "pong (0ms)");
+    }
+
+    #[test]
+    fn version_shows_the_crate_version() {
+        let version = version_message();
+        assert!(version.starts_with("frogbot v"));
+        assert!(version.contains(env!("CARGO_PKG_VERSION")));
+    }
+
+    /// A config with `@admin:example.com` as its only admin.
+    fn admin_config() -> Config {
+        let mut config = Config::default();
+        config.admins = vec![UserId::parse("@admin:example.com").unwrap()];
+        config
+    }
+
+    #[test]
+    fn only_admins_can_leave() {
+        let config = admin_config();
+        let leave = find_command("leave").unwrap();
+        let admin = UserId::parse("@admin:example.com").unwrap();
+        let someone = UserId::parse("@someone:example.com").unwrap();
+        assert!(can_run(leave, &admin, &config));
+        assert!(!can_run(leave, &someone, &config));
+        assert!(can_run(find_command("ping").unwrap(), &someone, &config));
+    }
+
+    #[test]
+    fn non_admins_cant_join_rooms() {
+        let config = admin_config();
+        let join = find_command("join").unwrap();
+        let someone = UserId::parse("@someone:example.com").unwrap();
+        assert!(!can_run(join, &someone, &config));
+        assert!(!can_run(join, &someone, &Config::default()));
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let help = help_message("", "!");
+        for command in COMMANDS {
+            assert!(help.contains(&format!("!{} - {}", command.name, command.description)));
+        }
+        assert!(help.contains("!leave - Makes frogbot leave this room (admins only)"));
+    }
+
+    #[test]
+    fn help_shows_how_to_use_a_command() {
+        assert_eq!(
+            help_message("join", "?"),
+            "?join <room id or alias>\nMakes frogbot join a room (admins only)"
+        );
+        assert_eq!(
+            help_message("!ping", "!"),
+            "!ping\nChecks that frogbot is alive"
+        );
+        assert_eq!(
+            help_message("frobnicate", "!"),
+            "There's no command called 'frobnicate', try !help"
+        );
+    }
+
+    #[test]
+    fn rapid_commands_are_on_cooldown() {
+        let user = UserId::parse("@spammer:example.com").unwrap();
+        let other_user = UserId::parse("@patient:example.com").unwrap();
+        let cooldown = Duration::from_secs(60);
+        assert!(!is_on_cooldown(&user, "roll", cooldown));
+        assert!(is_on_cooldown(&user, "roll", cooldown));
+        assert!(!is_on_cooldown(&user, "ping", cooldown));
+        assert!(!is_on_cooldown(&other_user, "roll", cooldown));
+    }
+
+    #[test]
+    fn cooldowns_run_out() {
+        let user = UserId::parse("@waiter:example.com").unwrap();
+        let cooldown = Duration::from_millis(20);
+        assert!(!is_on_cooldown(&user, "roll", cooldown));
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!is_on_cooldown(&user, "roll", cooldown));
+    }
+
+    /// Builds the context for running `command` with `args` in `!room:example.com`.
+    async fn test_context(
+        homeserver: &FakeHomeserver,
+        fetcher: FakeFetcher,
+        command: &str,
+        args: &str,
+    ) -> CommandContext {
+        let client = homeserver.client().await;
+        let room = client
+            .get_joined_room(<&RoomId>::try_from("!room:example.com").unwrap())
+            .unwrap();
+        CommandContext {
+            event: message_event(serde_json::json!({
+                "msgtype": "m.text",
+                "body": format!("!{} {}", command, args),
+            })),
+            room,
+            client,
+            config: Arc::new(Config::default()),
+            embed_settings: Arc::default(),
+            status: Arc::new(Status::new()),
+            fetcher: Arc::new(fetcher),
+            reqwest_client: reqwest::Client::new(),
+            command: find_command(command).unwrap(),
+            args: args.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn embed_sends_the_embed_for_a_link() {
+        let url = "https://example.com/embed-command";
+        let mut fetcher = FakeFetcher::default();
+        fetcher.pages.insert(
+            url.to_string(),
+            r#"<html><head>
+            <meta property="og:title" content="Frogs">
+            <meta property="og:description" content="All about frogs">
+            </head></html>"#
+                .to_string(),
+        );
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let ctx = test_context(&homeserver, fetcher, "embed", url).await;
+
+        embed(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        assert_eq!(sent.len(), 1);
+        let body = sent[0]["body"].as_str().unwrap();
+        assert!(body.contains("Frogs"));
+        assert!(body.contains("All about frogs"));
+    }
+
+    #[tokio::test]
+    async fn embed_needs_a_web_link() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let ctx = test_context(&homeserver, FakeFetcher::default(), "embed", "ftp://frogs").await;
+
+        embed(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]["body"]
+            .as_str()
+            .unwrap()
+            .ends_with("Usage: !embed <url>"));
+    }
+
+    #[tokio::test]
+    async fn embed_errors_are_not_shown() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let ctx = test_context(
+            &homeserver,
+            FakeFetcher::default(),
+            "embed",
+            "http://internal-name/",
+        )
+        .await;
+
+        embed(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        assert_eq!(sent.len(), 1);
+        let body = sent[0]["body"].as_str().unwrap();
+        assert!(body.ends_with("Couldn't embed that link"));
+        assert!(!body.contains("no page at"));
+    }
+
+    #[tokio::test]
+    async fn rooms_lists_the_joined_rooms() {
+        let homeserver =
+            FakeHomeserver::with_rooms(&["!room:example.com", "!other:example.com"], vec![]).await;
+        let ctx = test_context(&homeserver, FakeFetcher::default(), "rooms", "").await;
+
+        rooms(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        let body = sent[0]["body"].as_str().unwrap();
+        assert!(body.contains("In 2 rooms:"));
+        assert!(body.contains("!room:example.com"));
+        assert!(body.contains("!other:example.com"));
+    }
+
+    #[tokio::test]
+    async fn rooms_cuts_off_long_lists() {
+        let room_ids: Vec<String> = (0..MAX_LISTED_ROOMS + 2)
+            .map(|i| format!("!room{}:example.com", i))
+            .chain(["!room:example.com".to_string()])
+            .collect();
+        let room_ids: Vec<&str> = room_ids.iter().map(String::as_str).collect();
+        let homeserver = FakeHomeserver::with_rooms(&room_ids, vec![]).await;
+        let ctx = test_context(&homeserver, FakeFetcher::default(), "rooms", "").await;
+
+        rooms(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        let body = sent[0]["body"].as_str().unwrap();
+        assert!(body.contains(&format!("In {} rooms:", MAX_LISTED_ROOMS + 3)));
+        assert!(body.ends_with("...and 3 more"));
+    }
+
+    #[test]
+    fn durations_are_formatted_for_people() {
+        assert_eq!(format_duration(Duration::from_secs(5)), "5s");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1m 5s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h 2m 5s");
+        assert_eq!(
+            format_duration(Duration::from_secs(2 * 86400 + 5)),
+            "2d 0h 0m 5s"
+        );
+    }
+
+    #[test]
+    fn uptime_messages_say_how_long() {
+        assert_eq!(
+            uptime_message(Duration::from_secs(3725), None),
+            "Up for 1h 2m 5s"
+        );
+        assert_eq!(
+            uptime_message(Duration::from_secs(3726), Some(Duration::from_secs(4))),
+            "Up for 1h 2m 6s, last synced 4s ago"
+        );
+    }
+
+    #[tokio::test]
+    async fn uptime_replies_with_the_uptime() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let mut ctx = test_context(&homeserver, FakeFetcher::default(), "uptime", "").await;
+        let mut status = Status::new();
+        status.started = Instant::now() - Duration::from_secs(3725);
+        ctx.status = Arc::new(status);
+
+        uptime(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        // The seconds depend on how long the test takes, the minutes won't
+        assert!(sent[0]["body"].as_str().unwrap().contains("Up for 1h 2m "));
+    }
+
+    #[test]
+    fn polls_need_a_question_and_enough_options() {
+        let poll = parse_poll(r#""Best frog?" Tree frog | Bullfrog | Poison dart frog"#).unwrap();
+        assert_eq!(poll.question, "Best frog?");
+        assert_eq!(poll.options, ["Tree frog", "Bullfrog", "Poison dart frog"]);
+
+        assert!(parse_poll("Best frog? Tree frog | Bullfrog").is_none());
+        assert!(parse_poll(r#""" Tree frog | Bullfrog"#).is_none());
+        assert!(parse_poll(r#""Best frog?" Tree frog"#).is_none());
+        let too_many = vec!["frog"; POLL_EMOJIS.len() + 1].join(" | ");
+        assert!(parse_poll(&format!("\"Best frog?\" {}", too_many)).is_none());
+    }
+
+    #[tokio::test]
+    async fn polls_get_a_reaction_for_each_option() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let args = r#""Best frog?" Tree frog | Bullfrog | Poison dart frog"#;
+        let ctx = test_context(&homeserver, FakeFetcher::default(), "poll", args).await;
+
+        poll(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        assert_eq!(
+            sent[0]["body"],
+            "📊 Best frog?\n1️⃣ Tree frog\n2️⃣ Bullfrog\n3️⃣ Poison dart frog"
+        );
+        let reactions: Vec<String> = homeserver
+            .requests("PUT", "/send/m.reaction/")
+            .iter()
+            .map(|request| {
+                let reaction: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+                reaction["m.relates_to"]["key"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(reactions, ["1️⃣", "2️⃣", "3️⃣"]);
+    }
+
+    #[test]
+    fn polls_run_in_the_background() {
+        // A reaction for every option can take a while if frogbot gets rate limited
+        assert!(find_command("poll").unwrap().background);
+    }
+
+    #[test]
+    fn dice_notation_is_parsed() {
+        assert_eq!(
+            parse_dice("1d20"),
+            Some(Dice {
+                count: 1,
+                sides: 20,
+                modifier: 0
+            })
+        );
+        assert_eq!(
+            parse_dice("3D6+2"),
+            Some(Dice {
+                count: 3,
+                sides: 6,
+                modifier: 2
+            })
+        );
+        assert_eq!(
+            parse_dice("d8-1"),
+            Some(Dice {
+                count: 1,
+                sides: 8,
+                modifier: -1
+            })
+        );
+        for notation in [
+            "banana",
+            "2d",
+            "d1",
+            "0d6",
+            "101d6",
+            "1d1001",
+            "1d6+10001",
+            "1d6+x",
+        ] {
+            assert_eq!(
+                parse_dice(notation),
+                None,
+                "{} should be rejected",
+                notation
+            );
+        }
+    }
+
+    #[test]
+    fn dice_rolls_add_up() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let dice = parse_dice("3d6+2").unwrap();
+        let (rolls, total) = roll_dice(&dice, &mut StdRng::seed_from_u64(42)).unwrap();
+        assert_eq!(rolls.len(), 3);
+        assert!(rolls.iter().all(|roll| (1..=6).contains(roll)));
+        assert_eq!(
+            total,
+            rolls.iter().map(|roll| i64::from(*roll)).sum::<i64>() + 2
+        );
+        // The same seed always rolls the same
+        assert_eq!(
+            roll_dice(&dice, &mut StdRng::seed_from_u64(42)),
+            Some((rolls, total))
+        );
+
+        let (rolls, total) =
+            roll_dice(&parse_dice("1d20").unwrap(), &mut StdRng::seed_from_u64(7)).unwrap();
+        assert!((1..=20).contains(&rolls[0]));
+        assert_eq!(total, i64::from(rolls[0]));
+    }
+
+    #[tokio::test]
+    async fn bad_dice_get_the_usage() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let ctx = test_context(&homeserver, FakeFetcher::default(), "roll", "banana").await;
+
+        roll(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        assert!(sent[0]["body"]
+            .as_str()
+            .unwrap()
+            .ends_with("Usage: !roll <dice, e.g. 2d6+1>"));
+    }
+
+    #[tokio::test]
+    async fn announcements_go_to_every_room() {
+        let homeserver = FakeHomeserver::with_rooms(
+            &[
+                "!room:example.com",
+                "!other:example.com",
+                "!failing:example.com",
+            ],
+            vec![(
+                "PUT",
+                "failing",
+                serde_json::json!({ "errcode": "M_FORBIDDEN", "error": "Can't talk here" }),
+            )],
+        )
+        .await;
+        let ctx = test_context(
+            &homeserver,
+            FakeFetcher::default(),
+            "announce",
+            "Restarting soon",
+        )
+        .await;
+
+        announce(&ctx).await.unwrap();
+        assert!(homeserver
+            .sent_messages()
+            .iter()
+            .any(|message| message["body"]
+                .as_str()
+                .unwrap()
+                .ends_with("Announcing in 3 rooms")));
+
+        // The rooms are sent to in the background, with a pause between each of them
+        let sends = || homeserver.requests("PUT", "/send/m.room.message/");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while sends().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let sends = sends();
+        let announced_in = |room: &str| {
+            sends
+                .iter()
+                .filter(|request| request.method_and_path.contains(room))
+                .any(|request| request.body.contains("📢 Restarting soon"))
+        };
+        assert!(announced_in("other"));
+        assert!(announced_in("failing"));
+        assert_eq!(
+            sends
+                .iter()
+                .filter(|request| request.body.contains("📢 Restarting soon"))
+                .count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_announcements_get_the_usage() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let ctx = test_context(&homeserver, FakeFetcher::default(), "announce", "").await;
+
+        announce(&ctx).await.unwrap();
+        let sent = homeserver.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]["body"]
+            .as_str()
+            .unwrap()
+            .contains("announce <message>"));
+    }
+}
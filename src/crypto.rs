@@ -0,0 +1,211 @@
+//! # The Crypto Module
+//!
+//! This module sets up frogbot's end-to-end encryption identity, and sends the requests that need
+//! the account's password to manage it.
+
+use crate::Config;
+use anyhow::Context;
+use log::{debug, info, warn};
+use matrix_sdk::{
+    ruma::{api::client::uiaa, OwnedDeviceId},
+    Client,
+};
+
+use std::{ffi::OsString, time::Duration};
+
+/// How often the room keys are backed up while frogbot is running.
+pub const ROOM_KEY_BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A request the homeserver wants the account's password for, on top of the access token.
+pub enum PasswordRequest<'a> {
+    /// Deletes these devices from the bot's account
+    DeleteDevices(&'a [OwnedDeviceId]),
+    /// Uploads new cross-signing keys for the bot's account
+    BootstrapCrossSigning,
+}
+
+impl PasswordRequest<'_> {
+    /// Sends the request, with `auth` as the "user interaction" if there is any.
+    async fn send(
+        &self,
+        client: &Client,
+        auth: Option<uiaa::AuthData<'_>>,
+    ) -> matrix_sdk::Result<()> {
+        match self {
+            PasswordRequest::DeleteDevices(devices) => {
+                client.delete_devices(devices, auth).await?;
+            }
+            PasswordRequest::BootstrapCrossSigning => {
+                client.encryption().bootstrap_cross_signing(auth).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sends `request`, sending the password again if the homeserver asks for "user interaction".
+///
+/// Returns `false` if the password was asked for but none is configured, which happens when
+/// logging in with an access token.
+pub async fn send_with_password(
+    client: &Client,
+    config: &Config,
+    request: PasswordRequest<'_>,
+) -> anyhow::Result<bool> {
+    let Err(e) = request.send(client, None).await else {
+        return Ok(true);
+    };
+    let Some(info) = e.uiaa_response() else {
+        return Err(e.into());
+    };
+    if config.password.is_empty() {
+        return Ok(false);
+    }
+
+    let mut password = uiaa::Password::new(
+        uiaa::UserIdentifier::UserIdOrLocalpart(&config.username),
+        &config.password,
+    );
+    password.session = info.session.as_deref();
+    request
+        .send(client, Some(uiaa::AuthData::Password(password)))
+        .await?;
+    Ok(true)
+}
+
+/// Sets up cross-signing for the bot user, so its messages don't show up as unverified.
+///
+/// The new keys are kept in the store directory, so this only does anything on the first run.
+/// Without a store the keys would be lost on every restart and replaced on the next one, so
+/// nothing gets set up at all.
+pub async fn bootstrap_cross_signing(client: &Client, config: &Config) -> anyhow::Result<()> {
+    if config.store_path.is_none() {
+        warn!("No store_path configured, so cross-signing can't be set up");
+        return Ok(());
+    }
+
+    if let Some(status) = client.encryption().cross_signing_status().await {
+        if status.is_complete() {
+            return Ok(());
+        }
+    }
+
+    info!("Setting up cross-signing");
+    // Uploading the keys needs "user interaction" just like deleting devices does
+    if !send_with_password(client, config, PasswordRequest::BootstrapCrossSigning).await? {
+        warn!("No password configured, so cross-signing can't be set up");
+        return Ok(());
+    }
+    info!("Finished setting up cross-signing");
+    Ok(())
+}
+
+/// Imports the room keys from `room_key_backup_path`, if it's set and a backup has been made.
+///
+/// A backup that can't be read is an error rather than being skipped, since the next backup would
+/// replace it with one that's missing all of its keys.
+pub async fn restore_room_keys(client: &Client, config: &Config) -> anyhow::Result<()> {
+    let Some(backup_path) = &config.room_key_backup_path else {
+        return Ok(());
+    };
+    if !backup_path.exists() {
+        info!(
+            "No room key backup at '{}' yet, one will be made while running",
+            backup_path.display()
+        );
+        return Ok(());
+    }
+
+    let result = client
+        .encryption()
+        .import_room_keys(backup_path.clone(), &config.room_key_backup_passphrase)
+        .await
+        .with_context(|| {
+            format!(
+                "Couldn't restore room keys from '{}', check room_key_backup_passphrase",
+                backup_path.display()
+            )
+        })?;
+    info!(
+        "Restored {} new room keys from the {} in the backup",
+        result.imported_count, result.total_count
+    );
+    Ok(())
+}
+
+/// Exports every room key frogbot has to `room_key_backup_path`, encrypted with
+/// `room_key_backup_passphrase`.
+///
+/// The keys are written to a file next to the backup first and then moved over it, so stopping
+/// halfway through never leaves a broken backup behind.
+pub async fn back_up_room_keys(client: &Client, config: &Config) -> anyhow::Result<()> {
+    let Some(backup_path) = &config.room_key_backup_path else {
+        return Ok(());
+    };
+    let mut partial_path = OsString::from(backup_path);
+    partial_path.push(".partial");
+
+    client
+        .encryption()
+        .export_room_keys(
+            partial_path.clone().into(),
+            &config.room_key_backup_passphrase,
+            |_| true,
+        )
+        .await?;
+    std::fs::rename(&partial_path, backup_path)
+        .with_context(|| format!("Couldn't write '{}'", backup_path.display()))?;
+    debug!("Backed up room keys to '{}'", backup_path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_dir, valid_config, FakeHomeserver};
+
+    #[tokio::test]
+    async fn room_keys_are_backed_up_and_restored() {
+        let homeserver = FakeHomeserver::start(vec![]).await;
+        let mut config = valid_config();
+        config.room_key_backup_path = Some(test_dir("room-key-backup").join("room-keys.txt"));
+        config.room_key_backup_passphrase = "correct horse battery staple".to_string();
+
+        // There's no backup on the first run, so there's nothing to restore yet
+        let client = homeserver.client().await;
+        restore_room_keys(&client, &config).await.unwrap();
+        back_up_room_keys(&client, &config).await.unwrap();
+        let backup = std::fs::read_to_string(config.room_key_backup_path.as_ref().unwrap());
+        assert!(backup.unwrap().contains("BEGIN MEGOLM SESSION DATA"));
+
+        // The next run finds it
+        let client = homeserver.client().await;
+        restore_room_keys(&client, &config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn backups_with_the_wrong_passphrase_are_errors() {
+        let homeserver = FakeHomeserver::start(vec![]).await;
+        let client = homeserver.client().await;
+        let mut config = valid_config();
+        config.room_key_backup_path = Some(test_dir("room-key-passphrase").join("room-keys.txt"));
+        config.room_key_backup_passphrase = "correct horse battery staple".to_string();
+        back_up_room_keys(&client, &config).await.unwrap();
+
+        config.room_key_backup_passphrase = "incorrect horse".to_string();
+        let error = restore_room_keys(&client, &config).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("check room_key_backup_passphrase"));
+    }
+
+    #[tokio::test]
+    async fn nothing_is_backed_up_without_a_backup_path() {
+        let homeserver = FakeHomeserver::start(vec![]).await;
+        let client = homeserver.client().await;
+        let config = valid_config();
+
+        restore_room_keys(&client, &config).await.unwrap();
+        back_up_room_keys(&client, &config).await.unwrap();
+    }
+}
@@ -2,67 +2,832 @@
 //!
 //! This module controls the embed functionality of frogbot.
 
+use crate::{
+    cache::TtlCache, commands::parse_command, metrics, receipts, retry_rate_limited, store,
+    BotState, Config,
+};
+use anyhow::bail;
+use encoding_rs::{Encoding, UTF_8};
+use futures::stream::{self, Stream, StreamExt};
 use lazy_static::lazy_static;
-use log::warn;
+use log::{debug, info, warn};
 use matrix_sdk::{
-    room::Room,
-    ruma::events::room::message::{
-        MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+    event_handler::Ctx,
+    room::{Joined, Room},
+    ruma::{
+        events::room::{
+            message::{
+                FormattedBody, ImageMessageEventContent, MessageFormat, MessageType,
+                OriginalRoomMessageEvent, OriginalSyncRoomMessageEvent, Relation,
+                RoomMessageEventContent, Thread,
+            },
+            redaction::OriginalSyncRoomRedactionEvent,
+            ImageInfo,
+        },
+        EventId, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
     },
     Client,
 };
 use regex::Regex;
 use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::lookup_host,
+    sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit},
+};
+use tracing::{instrument, Instrument};
+use url::{Host, Url};
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// The user agent sent when fetching pages, unless one is set in the [`Config`].
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36";
+
+/// The maximum number of pages fetched at the same time for a single message.
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
+/// The maximum number of messages whose embeds are being worked on at the same time.
+const MAX_EMBED_TASKS: usize = 64;
+
+/// The maximum number of embeds kept in the cache.
+const MAX_CACHED_EMBEDS: usize = 256;
+
+/// The shortest a paragraph can be to get used as a description, so bylines and captions don't.
+const MIN_PARAGRAPH_CHARS: usize = 40;
+
+/// The maximum number of messages whose URLs are remembered for handling edits.
+const MAX_TRACKED_MESSAGES: usize = 1024;
+
+lazy_static! {
+    /// When each domain can next have a page fetched from it, used to rate limit embeds per domain
+    static ref NEXT_FETCH: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+    /// Recently generated embeds, along with when they were fetched
+    static ref EMBED_CACHE: Mutex<TtlCache<String, Option<Embed>>> =
+        Mutex::new(TtlCache::new(MAX_CACHED_EMBEDS));
+    /// The URLs found in recent messages
+    static ref MESSAGE_URLS: Mutex<TtlCache<OwnedEventId, HashSet<String>>> =
+        Mutex::new(TtlCache::new(MAX_TRACKED_MESSAGES));
+    // Selectors used to get metadata, compiled once instead of on every page
+    static ref OG_TITLE_SELECTOR: Selector = Selector::parse("meta[property=\"og:title\"]").unwrap();
+    static ref OG_DESCRIPTION_SELECTOR: Selector = Selector::parse("meta[property=\"og:description\"]").unwrap();
+    static ref TITLE_SELECTOR: Selector = Selector::parse("title").unwrap();
+    static ref DESCRIPTION_SELECTOR: Selector = Selector::parse("meta[name=\"description\"]").unwrap();
+    static ref TWITTER_TITLE_SELECTOR: Selector = Selector::parse("meta[name=\"twitter:title\"]").unwrap();
+    static ref TWITTER_DESCRIPTION_SELECTOR: Selector = Selector::parse("meta[name=\"twitter:description\"]").unwrap();
+    static ref OG_IMAGE_SELECTOR: Selector = Selector::parse("meta[property=\"og:image\"]").unwrap();
+    static ref CANONICAL_SELECTOR: Selector = Selector::parse("link[rel=\"canonical\"]").unwrap();
+    static ref H1_SELECTOR: Selector = Selector::parse("h1").unwrap();
+    static ref PARAGRAPH_SELECTOR: Selector = Selector::parse("p").unwrap();
+    static ref OG_SITE_NAME_SELECTOR: Selector = Selector::parse("meta[property=\"og:site_name\"]").unwrap();
+    static ref HTML_LANG_SELECTOR: Selector = Selector::parse("html[lang]").unwrap();
+    static ref OG_LOCALE_SELECTOR: Selector = Selector::parse("meta[property=\"og:locale\"]").unwrap();
+    static ref JSON_LD_SELECTOR: Selector = Selector::parse("script[type=\"application/ld+json\"]").unwrap();
+    static ref LINK_SELECTOR: Selector = Selector::parse("a[href]").unwrap();
+}
+
+lazy_static! {
+    /// Limits how many messages can have embeds in progress at once
+    static ref EMBED_TASKS: Arc<Semaphore> = Arc::new(Semaphore::new(MAX_EMBED_TASKS));
+}
+
+/// Limits how many embeds are fetched at the same time across every room.
+///
+/// It's sized from the [`Config`] the first time an embed is fetched.
+static FETCH_PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// The name of the file [`EmbedSettings`] are saved to in the store directory.
+pub const EMBED_SETTINGS_FILE: &str = "embed_settings.json";
+
+/// Embed settings that get changed at runtime with commands, rather than in the [`Config`].
+#[derive(Default, Serialize, Deserialize)]
+pub struct EmbedSettings {
+    /// Rooms where embeds were turned on or off, overriding the default from the [`Config`]
+    pub rooms: HashMap<OwnedRoomId, bool>,
+    /// Users who don't want their links embedded, in any room
+    #[serde(default)]
+    pub opted_out_users: HashSet<OwnedUserId>,
+}
+
+impl EmbedSettings {
+    /// Checks if embeds are turned on in a room.
+    pub fn embeds_enabled(&self, room_id: &RoomId, config: &Config) -> bool {
+        self.rooms
+            .get(room_id)
+            .copied()
+            .unwrap_or(config.embeds_enabled_by_default)
+    }
+
+    /// Checks if a user has opted out of having their links embedded.
+    pub fn is_opted_out(&self, user_id: &UserId) -> bool {
+        self.opted_out_users.contains(user_id)
+    }
+}
+
+/// The name of the file [`EmbedReplies`] are saved to in the store directory.
+pub const EMBED_REPLIES_FILE: &str = "embed_replies.json";
+
+/// The maximum number of messages whose embed replies are remembered for handling redactions.
+const MAX_TRACKED_REPLIES: usize = 1024;
+
+/// The replies frogbot sent for each message, so they can be redacted along with it.
+#[derive(Default, Serialize, Deserialize)]
+pub struct EmbedReplies {
+    /// The replies sent for each message, keyed by the message's event ID
+    replies: HashMap<OwnedEventId, Vec<OwnedEventId>>,
+    /// The messages in the order they were first replied to, oldest first
+    order: VecDeque<OwnedEventId>,
+}
+
+impl EmbedReplies {
+    /// Records that `reply_id` was sent in reply to `source_id`.
+    pub fn insert(&mut self, source_id: &EventId, reply_id: OwnedEventId) {
+        if !self.replies.contains_key(source_id) {
+            // Forget the oldest message once we're tracking too many
+            if self.order.len() >= MAX_TRACKED_REPLIES {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.replies.remove(&oldest);
+                }
+            }
+            self.order.push_back(source_id.to_owned());
+        }
+        self.replies
+            .entry(source_id.to_owned())
+            .or_default()
+            .push(reply_id);
+    }
 
-use std::time::Instant;
+    /// Forgets and returns the replies sent for `source_id`.
+    pub fn remove(&mut self, source_id: &EventId) -> Vec<OwnedEventId> {
+        self.order.retain(|event_id| event_id != source_id);
+        self.replies.remove(source_id).unwrap_or_default()
+    }
+}
 
 /// Represents an Embed in the chat
+#[derive(Clone, Debug)]
 pub struct Embed {
     /// The title of the embed
     pub title: String,
     /// The description
     pub description: String,
+    /// The URL of the preview image, if the page has one
+    ///
+    /// Only `mxc://` images are shown, so the page's own image has to go through
+    /// [`upload_thumbnail`] first.
+    pub image: Option<String>,
+    /// The canonical URL of the page, if it has one
+    pub canonical_url: Option<String>,
+    /// A URL the title links to, for embeds that aren't a reply to the link itself
+    pub link: Option<String>,
+    /// The name of the site the page is on, if it says (e.g. "Example Blog")
+    pub site_name: Option<String>,
+    /// The language the page is in, if it says (e.g. "French")
+    pub language: Option<String>,
 }
 
 impl Embed {
     /// Creates a new [`Embed`].
     pub fn new(title: String, description: String) -> Embed {
-        Embed { title, description }
+        Embed {
+            title,
+            description,
+            image: None,
+            canonical_url: None,
+            link: None,
+            site_name: None,
+            language: None,
+        }
+    }
+
+    /// Builds the reply frogbot sends for this embed.
+    pub fn to_message_content(
+        &self,
+        config: &Config,
+        reply_to: &OriginalRoomMessageEvent,
+    ) -> RoomMessageEventContent {
+        let description = truncate_text(&self.description, config.embed_description_max_chars);
+        // Matrix only allows mxc:// images, anything else would have clients load it straight from
+        // the linked site
+        let html_image = match self.image.as_deref() {
+            Some(image) if image.starts_with("mxc://") => format!(
+                "<img src=\"{}\">",
+                html_escape::encode_double_quoted_attribute(image)
+            ),
+            _ => String::new(),
+        };
+        // The page controls the title and description, so they have to be escaped to keep it
+        // from injecting its own markup into the reply
+        let (plain_title, html_title) = match &self.link {
+            Some(link) => (
+                format!("{} {}", self.title, link),
+                format!(
+                    "<a href=\"{}\">{}</a>",
+                    html_escape::encode_double_quoted_attribute(link),
+                    html_escape::encode_text(&self.title)
+                ),
+            ),
+            None => (
+                self.title.clone(),
+                html_escape::encode_text(&self.title).to_string(),
+            ),
+        };
+        // A small line above the title saying where the page is from, and what language it's in
+        let label: Vec<String> = self
+            .site_name
+            .iter()
+            .map(|site_name| format!("via {}", site_name))
+            .chain(self.language.clone().filter(|_| config.embed_show_language))
+            .collect();
+        let (plain_site_name, html_site_name) = match label.is_empty() {
+            true => (String::new(), String::new()),
+            false => (
+                format!("{}\n", label.join(" · ")),
+                format!(
+                    "<sub>{}</sub>",
+                    html_escape::encode_text(&label.join(" · "))
+                ),
+            ),
+        };
+        let content = RoomMessageEventContent::text_html(
+            format!("{}{}", plain_site_name, plain_title),
+            format!(
+                "<blockquote>
+                {}
+                <h4>{}</h4>
+                <p>{}</p>
+                {}
+                </blockquote>",
+                html_site_name,
+                html_title,
+                html_escape::encode_text(&description),
+                html_image
+            ),
+        );
+        reply_to_message(content, reply_to)
+    }
+}
+
+/// Builds the reply frogbot sends when a page has no metadata to embed, if it's configured to
+/// send one.
+fn no_metadata_reply(
+    config: &Config,
+    reply_to: &OriginalRoomMessageEvent,
+) -> Option<RoomMessageEventContent> {
+    if !config.reply_on_no_metadata {
+        return None;
+    }
+    let content = RoomMessageEventContent::text_html(
+        "Couldn't parse metadata for URL",
+        "<blockquote><h5>Couldn't parse metadata for URL</h5></blockquote>",
+    );
+    Some(reply_to_message(content, reply_to))
+}
+
+/// Makes `content` a reply to `reply_to`, keeping it in the same thread if `reply_to` is in one.
+fn reply_to_message(
+    content: RoomMessageEventContent,
+    reply_to: &OriginalRoomMessageEvent,
+) -> RoomMessageEventContent {
+    let mut content = content.make_reply_to(reply_to);
+    // A plain reply would pop the embed out of the thread and into the main timeline
+    if let Some(Relation::Thread(thread)) = &reply_to.content.relates_to {
+        content.relates_to = Some(Relation::Thread(Thread::reply(
+            thread.event_id.clone(),
+            reply_to.event_id.clone(),
+        )));
+    }
+    content
+}
+
+/// The parts of an oEmbed response used to build an [`Embed`].
+#[derive(Deserialize)]
+struct OEmbed {
+    title: Option<String>,
+    author_name: Option<String>,
+    thumbnail_url: Option<String>,
+}
+
+/// The ways scraping an [`Embed`] out of a page can fail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EmbedParseError {
+    /// The page is a document, but has no title or description to embed
+    NoMetadata,
+    /// The page isn't an HTML document at all (e.g. it's empty or has no tags)
+    Malformed,
+}
+
+impl fmt::Display for EmbedParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbedParseError::NoMetadata => write!(f, "page has no title or description"),
+            EmbedParseError::Malformed => write!(f, "page isn't an HTML document"),
+        }
     }
 }
 
+impl std::error::Error for EmbedParseError {}
+
 /// Scrapes the HTML of a webpage and generates an [`Embed`] with the scraped information.
-pub fn parse_metadata(page: &str) -> Option<Embed> {
+///
+/// JSON-LD structured data is preferred when present, since news and recipe sites put their best
+/// metadata there. OpenGraph tags come next, falling back to the `<title>` element and the
+/// standard description meta tag for any field neither of those provides. Twitter Card tags are
+/// used when none of those has the field, and when there are no tags at all the first `<h1>`
+/// and the first substantial `<p>` are used as a last resort.
+#[instrument(level = "debug", skip_all)]
+pub fn parse_metadata(page: &str) -> Result<Embed, EmbedParseError> {
+    // The HTML parser accepts anything, so catch pages that clearly aren't markup ourselves
+    if !page.contains('<') {
+        return Err(EmbedParseError::Malformed);
+    }
+
     let doc_body = Html::parse_document(page);
 
-    // Selectors used to get metadata are defined here
-    let title_selector = Selector::parse("title").unwrap();
-    let description_selector = Selector::parse("meta[name=\"description\"]").unwrap();
+    // Grab the actual data, using whichever source actually has content
+    let json_ld = json_ld_metadata(&doc_body);
+    let title = json_ld
+        .title
+        .or_else(|| meta_content(&doc_body, &OG_TITLE_SELECTOR))
+        .or_else(|| {
+            doc_body
+                .select(&TITLE_SELECTOR)
+                .next()
+                .map(|title| title.text().collect::<String>().trim().to_string())
+                .filter(|title| !title.is_empty())
+        })
+        .or_else(|| meta_content(&doc_body, &TWITTER_TITLE_SELECTOR))
+        .or_else(|| element_text(&doc_body, &H1_SELECTOR, 1));
+    // Pages without any description tags usually still say what they're about in their text
+    let description = json_ld
+        .description
+        .or_else(|| meta_content(&doc_body, &OG_DESCRIPTION_SELECTOR))
+        .or_else(|| meta_content(&doc_body, &DESCRIPTION_SELECTOR))
+        .or_else(|| meta_content(&doc_body, &TWITTER_DESCRIPTION_SELECTOR))
+        .or_else(|| element_text(&doc_body, &PARAGRAPH_SELECTOR, MIN_PARAGRAPH_CHARS));
+
+    if let (None, None) = (&title, &description) {
+        return Err(EmbedParseError::NoMetadata);
+    }
+
+    // Some sites double-escape their metadata, so decode any entities that are left over
+    let mut title = html_escape::decode_html_entities(&title.unwrap_or_default()).into_owned();
+    let description =
+        html_escape::decode_html_entities(&description.unwrap_or_default()).into_owned();
+
+    let site_name = meta_content(&doc_body, &OG_SITE_NAME_SELECTOR)
+        .map(|site_name| html_escape::decode_html_entities(&site_name).into_owned());
+    if let Some(site_name) = &site_name {
+        title = strip_site_name(&title, site_name).to_string();
+    }
+
+    let mut embed = Embed::new(title, description);
+    embed.site_name = site_name;
+    embed.language = doc_body
+        .select(&HTML_LANG_SELECTOR)
+        .next()
+        .and_then(|html| html.value().attr("lang"))
+        .map(str::to_string)
+        .or_else(|| meta_content(&doc_body, &OG_LOCALE_SELECTOR))
+        .and_then(|language| language_name(&language));
+    embed.image = meta_content(&doc_body, &OG_IMAGE_SELECTOR);
+    embed.canonical_url = doc_body
+        .select(&CANONICAL_SELECTOR)
+        .next()
+        .and_then(|link| link.value().attr("href"))
+        .map(str::trim)
+        .filter(|href| !href.is_empty())
+        .map(str::to_string);
 
-    // Grab the actual data
-    let title = doc_body.select(&title_selector).next();
-    let desc = doc_body.select(&description_selector).next();
-    // Clean up meta info and store it as a string
-    let mut meta_title = String::default();
-    let mut meta_description = String::default();
+    Ok(embed)
+}
 
-    if let (None, None) = (title, desc) {
+/// Turns a language tag like `en-US` or a locale like `fr_FR` into the name of the language.
+///
+/// Languages that aren't in the list are shown as their uppercased code (e.g. "EO").
+fn language_name(tag: &str) -> Option<String> {
+    let code = tag
+        .trim()
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    if code.is_empty() || !code.chars().all(|c| c.is_ascii_alphabetic()) {
         return None;
     }
 
-    if let Some(title) = title {
-        meta_title = title.text().collect();
+    let name = match code.as_str() {
+        "ar" => "Arabic",
+        "cs" => "Czech",
+        "da" => "Danish",
+        "de" => "German",
+        "el" => "Greek",
+        "en" => "English",
+        "es" => "Spanish",
+        "fi" => "Finnish",
+        "fr" => "French",
+        "he" => "Hebrew",
+        "hi" => "Hindi",
+        "hu" => "Hungarian",
+        "id" => "Indonesian",
+        "it" => "Italian",
+        "ja" => "Japanese",
+        "ko" => "Korean",
+        "nl" => "Dutch",
+        "no" | "nb" | "nn" => "Norwegian",
+        "pl" => "Polish",
+        "pt" => "Portuguese",
+        "ro" => "Romanian",
+        "ru" => "Russian",
+        "sv" => "Swedish",
+        "th" => "Thai",
+        "tr" => "Turkish",
+        "uk" => "Ukrainian",
+        "vi" => "Vietnamese",
+        "zh" => "Chinese",
+        _ => return Some(code.to_uppercase()),
+    };
+    Some(name.to_string())
+}
+
+/// Removes a trailing " - Site Name" or " | Site Name" from `title`.
+///
+/// Only an exact match of the whole site name right at the end gets removed, so dashes and pipes
+/// that are actually part of the title are left alone.
+fn strip_site_name<'a>(title: &'a str, site_name: &str) -> &'a str {
+    for separator in [" - ", " | ", " – ", " — "] {
+        if let Some(stripped) = title
+            .strip_suffix(site_name)
+            .and_then(|title| title.strip_suffix(separator))
+        {
+            if !stripped.trim().is_empty() {
+                return stripped.trim_end();
+            }
+        }
+    }
+    title
+}
+
+/// Resolves a possibly relative or protocol-relative URL against the URL of the page it was
+/// found on.
+fn resolve_url(page_url: &str, url: &str) -> Option<String> {
+    Url::parse(page_url)
+        .and_then(|base| base.join(url))
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .map(|url| url.to_string())
+}
+
+/// Resolves the canonical URL a page claims, keeping it only if it's on the same host the page was
+/// fetched from.
+///
+/// Any page can claim any canonical URL, so one on another host would let a page choose the
+/// embed shown for someone else's link.
+fn same_host_canonical_url(page_url: &str, canonical_url: &str) -> Option<String> {
+    let page_host = Url::parse(page_url).ok()?.host_str()?.to_lowercase();
+    let canonical_url = resolve_url(page_url, canonical_url)?;
+    let canonical_host = Url::parse(&canonical_url).ok()?.host_str()?.to_lowercase();
+    (canonical_host == page_host).then_some(canonical_url)
+}
+
+/// The JSON-LD types whose `name` is about the site or someone on it rather than the page, like
+/// the publisher of an article.
+const JSON_LD_NON_PAGE_TYPES: &[&str] = &[
+    "BreadcrumbList",
+    "ImageObject",
+    "ListItem",
+    "Organization",
+    "Person",
+    "SearchAction",
+    "WebSite",
+];
+
+/// What a page's JSON-LD structured data says about it.
+#[derive(Default)]
+struct JsonLdMetadata {
+    /// The `headline` or `name` of the page
+    title: Option<String>,
+    /// The `description` of the page
+    description: Option<String>,
+}
+
+/// Gets the title and description out of the JSON-LD blocks in a page.
+///
+/// Blocks can hold a single object, an array of them, or a `@graph` of them, and pages often
+/// describe their publisher and site next to the page itself, so the node that's actually about
+/// the page is picked out of all of them. Blocks that aren't valid JSON are skipped.
+fn json_ld_metadata(doc_body: &Html) -> JsonLdMetadata {
+    let mut nodes = vec![];
+    for script in doc_body.select(&JSON_LD_SELECTOR) {
+        let text: String = script.text().collect();
+        if let Ok(value) = serde_json::from_str(&text) {
+            collect_json_ld_nodes(value, &mut nodes);
+        }
+    }
+
+    // Headlines are only ever about the page, names could be about anything
+    let page_node = nodes
+        .iter()
+        .find(|node| json_ld_string(node, "headline").is_some())
+        .or_else(|| {
+            nodes
+                .iter()
+                .find(|node| !is_json_ld_non_page(node) && json_ld_string(node, "name").is_some())
+        });
+    let Some(page_node) = page_node else {
+        return JsonLdMetadata::default();
+    };
+
+    JsonLdMetadata {
+        title: json_ld_string(page_node, "headline").or_else(|| json_ld_string(page_node, "name")),
+        description: json_ld_string(page_node, "description"),
+    }
+}
+
+/// Flattens JSON-LD arrays and `@graph`s into a list of the objects in them.
+fn collect_json_ld_nodes(value: serde_json::Value, nodes: &mut Vec<serde_json::Value>) {
+    match value {
+        serde_json::Value::Array(items) => {
+            for item in items {
+                collect_json_ld_nodes(item, nodes);
+            }
+        }
+        serde_json::Value::Object(mut object) => {
+            if let Some(graph) = object.remove("@graph") {
+                collect_json_ld_nodes(graph, nodes);
+            }
+            nodes.push(serde_json::Value::Object(object));
+        }
+        _ => {}
+    }
+}
+
+/// Gets a string field of a JSON-LD node, if it isn't empty.
+fn json_ld_string(node: &serde_json::Value, key: &str) -> Option<String> {
+    node.get(key)
+        .and_then(serde_json::Value::as_str)
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+}
+
+/// Checks if a JSON-LD node is about something other than the page, going by its `@type`.
+fn is_json_ld_non_page(node: &serde_json::Value) -> bool {
+    let is_non_page = |kind: &serde_json::Value| {
+        kind.as_str()
+            .is_some_and(|kind| JSON_LD_NON_PAGE_TYPES.contains(&kind))
+    };
+    match node.get("@type") {
+        Some(serde_json::Value::Array(kinds)) => kinds.iter().any(is_non_page),
+        Some(kind) => is_non_page(kind),
+        None => false,
+    }
+}
+
+/// Gets the `content` attribute of the first meta tag matching `selector`, if it isn't empty.
+fn meta_content(doc_body: &Html, selector: &Selector) -> Option<String> {
+    doc_body
+        .select(selector)
+        .next()
+        .and_then(|meta| meta.value().attr("content"))
+        .map(str::trim)
+        .filter(|content| !content.is_empty())
+        .map(str::to_string)
+}
+
+/// Gets the text of the first element matching `selector` with at least `min_chars` characters,
+/// with its whitespace collapsed.
+fn element_text(doc_body: &Html, selector: &Selector, min_chars: usize) -> Option<String> {
+    doc_body
+        .select(selector)
+        .map(|element| {
+            element
+                .text()
+                .flat_map(str::split_whitespace)
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .find(|text| text.chars().count() >= min_chars)
+}
+
+/// Shortens `text` to at most `max_chars` characters, adding an ellipsis if anything was cut off.
+///
+/// This tries to break on a word boundary, and counts characters rather than bytes so multibyte
+/// characters never get split in half.
+fn truncate_text(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+
+    // Leave room for the ellipsis
+    let cut = text
+        .char_indices()
+        .nth(max_chars.saturating_sub(1))
+        .map(|(i, _)| i)
+        .unwrap_or(text.len());
+    let truncated = &text[..cut];
+
+    // Try not to cut a word in half
+    let truncated = match truncated.rfind(char::is_whitespace) {
+        Some(space) if space > 0 => &truncated[..space],
+        _ => truncated,
+    };
+    format!("{}…", truncated.trim_end())
+}
+
+/// Gets the URLs in a message that should be embedded.
+///
+/// Replies quote the message they're replying to, so for replies only URLs that the reply itself
+/// adds are returned. Otherwise replying to a link would embed it all over again.
+fn get_new_urls_from_message(message: &str, is_reply: bool) -> Vec<&str> {
+    if !is_reply {
+        return get_urls_from_message(message);
+    }
+
+    // The reply fallback is made of lines quoting the original message
+    let (quoted_lines, new_lines): (Vec<&str>, Vec<&str>) =
+        message.lines().partition(|line| line.starts_with('>'));
+    let quoted_urls: HashSet<&str> = quoted_lines
+        .into_iter()
+        .flat_map(get_urls_from_message)
+        .collect();
+
+    new_lines
+        .into_iter()
+        .flat_map(get_urls_from_message)
+        .filter(|url| !quoted_urls.contains(url))
+        .collect()
+}
+
+/// Gets the plain and formatted bodies of a message that could have links to embed.
+///
+/// Anything that isn't text is ignored, bots and bridges tend to post links as notices though.
+fn message_text(msgtype: MessageType) -> Option<(String, Option<FormattedBody>)> {
+    match msgtype {
+        MessageType::Text(content) => Some((content.body, content.formatted)),
+        MessageType::Notice(content) => Some((content.body, content.formatted)),
+        _ => None,
+    }
+}
+
+/// Gets the URLs to embed out of both the plain and formatted bodies of a message, with their
+/// tracking parameters stripped.
+///
+/// Messages with the opt-out marker in them don't have any.
+fn message_urls(
+    body: &str,
+    formatted: Option<FormattedBody>,
+    is_reply: bool,
+    config: &Config,
+) -> Vec<String> {
+    // Let people share a link without an embed when they don't want one
+    if has_opt_out_marker(body, &config.embed_opt_out_marker) {
+        return Vec::new();
+    }
+
+    let formatted_urls = formatted
+        .filter(|formatted| formatted.format == MessageFormat::Html)
+        .map(|formatted| get_urls_from_formatted_body(&formatted.body))
+        .unwrap_or_default();
+
+    get_new_urls_from_message(body, is_reply)
+        .into_iter()
+        .map(str::to_string)
+        .chain(formatted_urls)
+        .map(|url| strip_tracking_params(&url, &config.embed_tracking_params))
+        .collect()
+}
+
+/// Checks if a message contains `marker` as a word of its own, asking not to be embedded.
+fn has_opt_out_marker(message: &str, marker: &str) -> bool {
+    !marker.is_empty() && message.split_whitespace().any(|word| word == marker)
+}
+
+/// Gets the links out of the `href`s in a message's formatted HTML body.
+///
+/// Links in the reply fallback are skipped, since those belong to the message being replied to.
+/// So are mention pills and room links, which are `matrix.to` links rather than web pages.
+fn get_urls_from_formatted_body(formatted_body: &str) -> Vec<String> {
+    let formatted_body = formatted_body
+        .rsplit("</mx-reply>")
+        .next()
+        .unwrap_or(formatted_body);
+    Html::parse_fragment(formatted_body)
+        .select(&LINK_SELECTOR)
+        .filter_map(|link| link.value().attr("href"))
+        .filter(|href| is_web_link(href))
+        .flat_map(get_urls_from_message)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Checks if a link points to a web page, rather than a `matrix.to` mention or room link, or
+/// something like a `mailto:` link.
+fn is_web_link(href: &str) -> bool {
+    match Url::parse(href.trim()) {
+        Ok(url) => matches!(url.scheme(), "http" | "https") && url.host_str() != Some("matrix.to"),
+        Err(_) => false,
+    }
+}
+
+/// Checks if the `Content-Type` of a response says it is an HTML page.
+fn is_html_response(res: &reqwest::Response) -> bool {
+    res.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .map(|mime| {
+            let mime = mime.trim().to_ascii_lowercase();
+            mime == "text/html" || mime == "application/xhtml+xml"
+        })
+        .unwrap_or(false)
+}
+
+/// Figures out how a page is encoded, first from the `charset` in its `Content-Type` header and
+/// then from a `<meta charset>` tag, falling back to UTF-8.
+fn detect_encoding(content_type: Option<&str>, body: &[u8]) -> &'static Encoding {
+    lazy_static! {
+        // Matches both <meta charset="..."> and <meta http-equiv="Content-Type" content="...; charset=...">
+        static ref META_CHARSET: Regex =
+            Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?([a-z0-9_\-:.]+)"#).unwrap();
+    }
+
+    let header_charset = content_type.and_then(|content_type| {
+        content_type.split(';').skip(1).find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            name.trim()
+                .eq_ignore_ascii_case("charset")
+                .then(|| value.trim().trim_matches('"'))
+        })
+    });
+    if let Some(encoding) =
+        header_charset.and_then(|charset| Encoding::for_label(charset.as_bytes()))
+    {
+        return encoding;
     }
 
-    if let Some(desc) = desc {
-        meta_description = desc.value().attr("content").unwrap().to_string();
+    // The meta tag has to be near the start of the document, so only look there
+    let head = String::from_utf8_lossy(&body[..body.len().min(1024)]);
+    META_CHARSET
+        .captures(&head)
+        .and_then(|captures| Encoding::for_label(captures[1].as_bytes()))
+        .unwrap_or(UTF_8)
+}
+
+/// Removes tracking parameters like `utm_source` or `fbclid` from the query string of a URL.
+///
+/// Entries in `tracking_params` ending with `*` match any parameter starting with the rest of the
+/// entry, so `utm_*` covers all the `utm_` parameters.
+fn strip_tracking_params(url: &str, tracking_params: &[String]) -> String {
+    let Ok(mut parsed_url) = Url::parse(url) else {
+        return url.to_string();
+    };
+
+    let is_tracking_param = |name: &str| {
+        tracking_params
+            .iter()
+            .any(|param| match param.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == param,
+            })
+    };
+    let query_pairs: Vec<(String, String)> = parsed_url
+        .query_pairs()
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    let kept_pairs: Vec<&(String, String)> = query_pairs
+        .iter()
+        .filter(|(name, _)| !is_tracking_param(name))
+        .collect();
+
+    // Leave URLs without any tracking parameters exactly how they were posted
+    if kept_pairs.len() == query_pairs.len() {
+        return url.to_string();
     }
 
-    Some(Embed::new(meta_title, meta_description))
+    if kept_pairs.is_empty() {
+        parsed_url.set_query(None);
+    } else {
+        parsed_url
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(kept_pairs);
+    }
+    parsed_url.to_string()
 }
 
 /// Check if the message has any urls in it and get them if it does
-fn get_urls_from_message(message: &str) -> Vec<&str> {
+///
+/// Punctuation that ends the sentence a URL is in, and brackets wrapped around it, are trimmed
+/// off the end rather than treated as part of the URL.
+pub fn get_urls_from_message(message: &str) -> Vec<&str> {
     // Using lazy static magic here, so this means the regex is compiled exactly once
     // After initial compile it gets reused instead of recompiling on every message event
     lazy_static! {
@@ -77,14 +842,19 @@ fn get_urls_from_message(message: &str) -> Vec<&str> {
     if RE.is_match(message) {
         // If we find any urls, push them into the urls vec
         for regex_match in RE.find_iter(message) {
+            let mut url = regex_match.as_str();
+            // Markdown links end at the paren closing `[text](url)`, whatever comes after it
+            if message[..regex_match.start()].ends_with("](") {
+                url = cut_at_unmatched_paren(url);
+            }
+            let url = trim_url_end(url);
             // If the url points to localhost, we don't want to embed it, so we ignore it
-            if regex_match.as_str().to_lowercase().contains("localhost")
-                || regex_match.as_str().to_lowercase().contains("127.0.0.1")
+            if url.to_lowercase().contains("localhost") || url.to_lowercase().contains("127.0.0.1")
             {
                 warn!("This is probably a malicious URL, ignoring!");
             } else {
-                warn!("Found '{}'", &regex_match.as_str());
-                urls.push(regex_match.as_str());
+                debug!("Found '{}'", url);
+                urls.push(url);
             }
         }
     } else {
@@ -93,83 +863,3019 @@ fn get_urls_from_message(message: &str) -> Vec<&str> {
     urls
 }
 
-/// Checks messages for valid links and generates embeds if found
-pub async fn embed_handler(event: OriginalSyncRoomMessageEvent, room: Room, client: Client) {
-    let fn_start = Instant::now();
+/// Gets the links written in markdown as `[text](url)` out of a message, with their text.
+pub fn get_markdown_links(message: &str) -> Vec<(&str, &str)> {
+    lazy_static! {
+        static ref MARKDOWN_LINK: Regex = Regex::new(r"\[([^\[\]\n]+)\]\((https?://\S+)").unwrap();
+    }
 
-    if let Room::Joined(room) = room {
-        let full_reply_event = event.clone().into_full_event(room.room_id().to_owned());
+    MARKDOWN_LINK
+        .captures_iter(message)
+        .filter_map(|captures| {
+            let text = captures.get(1)?.as_str().trim();
+            let url = trim_url_end(cut_at_unmatched_paren(captures.get(2)?.as_str()));
+            Some((text, url))
+        })
+        .filter(|(text, _)| !text.is_empty())
+        .collect()
+}
 
-        // If the sender ID matches our client, ignore the message
-        // We don't want to reply to ourselves
-        let client_user_id = client.user_id().unwrap();
-        if event.sender == client_user_id {
-            return;
+/// Cuts `url` off at the first closing paren that doesn't have an opening one before it.
+fn cut_at_unmatched_paren(url: &str) -> &str {
+    let mut depth = 0;
+    for (i, c) in url.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return &url[..i],
+            ')' => depth -= 1,
+            _ => {}
         }
+    }
+    url
+}
 
-        // Do not make an embed if someone replies to a URL
-        // Unfortunately, this makes it so that if your reply has a URL, it will not embed.
-        // TODO: Fix this by scanning replies and only generating embeds for new URLs in future.
-        if let Some(Relation::Reply { in_reply_to: _ }) = &event.content.relates_to {
-            return;
+/// Trims trailing punctuation and unmatched closing brackets off the end of a URL.
+///
+/// Brackets that are matched inside the URL are kept, so links like
+/// `https://en.wikipedia.org/wiki/Frog_(disambiguation)` survive intact.
+fn trim_url_end(mut url: &str) -> &str {
+    loop {
+        let Some(last) = url.chars().last() else {
+            return url;
+        };
+        let unmatched =
+            |open: char, close: char| url.matches(close).count() > url.matches(open).count();
+        let trim = match last {
+            '.' | ',' | '!' | '?' | ';' | ':' | '\'' | '"' => true,
+            ')' => unmatched('(', ')'),
+            ']' => unmatched('[', ']'),
+            '}' => unmatched('{', '}'),
+            '>' => unmatched('<', '>'),
+            _ => false,
+        };
+        if !trim {
+            return url;
         }
+        url = &url[..url.len() - last.len_utf8()];
+    }
+}
 
-        // Ignore anything that isn't text
-        let MessageType::Text(text_content) = event.content.msgtype else {
-            return;
-        };
+/// Checks if an IP address points somewhere we shouldn't be making requests to on behalf of
+/// people in chat, like loopback, private networks, or the cloud metadata service.
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                // This also covers the cloud metadata address, 169.254.169.254
+                || ip.is_link_local()
+                || ip.is_multicast()
+                // "This network", 0.0.0.0/8, which Linux treats as the local machine
+                || octets[0] == 0
+                // Reserved, 240.0.0.0/4, which includes the broadcast address
+                || octets[0] >= 240
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (octets[0] == 100 && (octets[1] & 0b1100_0000) == 64)
+                // Benchmarking, 198.18.0.0/15
+                || (octets[0] == 198 && (octets[1] & 0b1111_1110) == 18)
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            // IPv4-mapped (::ffff:a.b.c.d) and IPv4-compatible (::a.b.c.d) addresses reach the
+            // IPv4 address they embed, this also covers :: and ::1
+            if let Some(ipv4) = ip.to_ipv4() {
+                return is_internal_ip(IpAddr::V4(ipv4));
+            }
+            // NAT64, 64:ff9b::/96, gets translated to the IPv4 address in its last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., high, low] = segments;
+                let ipv4 = std::net::Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+                return is_internal_ip(IpAddr::V4(ipv4));
+            }
+            ip.is_multicast()
+                // Local-use NAT64, 64:ff9b:1::/48, which only translates inside the local network
+                || segments[..3] == [0x64, 0xff9b, 1]
+                // Unique local, fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                // Site-local, fec0::/10, deprecated but still routed by some networks
+                || (segments[0] & 0xffc0) == 0xfec0
+        }
+    }
+}
 
-        let urls = get_urls_from_message(&text_content.body);
-        let reqwest_client = reqwest::Client::builder().user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0.0.0 Safari/537.36").build().unwrap();
+/// Makes sure the host of `url` doesn't point to any internal addresses.
+///
+/// Hostnames are resolved first, so something like `internal.example.com` pointing at a
+/// `192.168.x.x` address gets caught too. This is only a first check though, the page client's
+/// [`PublicResolver`] checks again on the lookup the connection is actually made with.
+async fn ensure_public_host(url: &Url) -> anyhow::Result<()> {
+    let addresses: Vec<IpAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![IpAddr::V4(ip)],
+        Some(Host::Ipv6(ip)) => vec![IpAddr::V6(ip)],
+        Some(Host::Domain(domain)) => {
+            let port = url.port_or_known_default().unwrap_or(80);
+            lookup_host((domain, port))
+                .await?
+                .map(|address| address.ip())
+                .collect()
+        }
+        None => bail!("URL has no host"),
+    };
 
-        for url in urls {
-            if let Ok(req) = reqwest_client.get(url).send().await {
-                if let Ok(res) = req.text().await {
-                    // beware, dirty HTML parsing code
-                    let metadata = parse_metadata(&res);
-                    warn!("Ran fn parse_metadata after: '{:#?}'", fn_start.elapsed());
-
-                    // Build and send our message reply
-                    if metadata.is_some() {
-                        let embed = metadata.unwrap();
-                        let bot_reply = RoomMessageEventContent::text_html(
-                            &embed.title,
-                            format!(
-                                "<blockquote>
-                                <h4>{}</h4>
-                                <p>{}</p>
-                                </blockquote>",
-                                &embed.title, &embed.description
-                            ),
-                        )
-                        .make_reply_to(&full_reply_event);
-
-                        // Finally send the reply to the room
-                        warn!("Sending embed for URL: '{}'", &url);
-                        if room.send(bot_reply, None).await.is_err() {
-                            warn!("Failed to send embed for URL: '{}'", &url);
-                        }
-                        warn!("Ran fn room.send after: '{:#?}'", fn_start.elapsed());
-                    // If we didn't get any metadata send a generic "No metadata" response
-                    } else {
-                        let bot_reply = RoomMessageEventContent::text_html(
-                            "Couldn't parse metadata for URL",
-                            "<blockquote><h5>Couldn't parse metadata for URL</h5></blockquote>",
-                        )
-                        .make_reply_to(&full_reply_event);
-                        // Send the reply to the room
-                        warn!("Sending 'No metadata' embed for URL: '{}'", &url);
-                        if room.send(bot_reply, None).await.is_err() {
-                            warn!("Failed to send embed for URL: '{}'", &url);
-                        }
-                        warn!("Ran fn room.send after: '{:#?}'", fn_start.elapsed());
-                    }
-                } else {
-                    warn!("Failed to parse HTML for URL: '{}'", &url);
+    if let Some(ip) = addresses.into_iter().find(|ip| is_internal_ip(*ip)) {
+        bail!("host points to internal address {}", ip);
+    }
+    Ok(())
+}
+
+/// Resolves hostnames for the page client, refusing any that point to internal addresses.
+///
+/// Checking on the same lookup the connection is made with means a DNS server can't answer with a
+/// public address for [`ensure_public_host`] and an internal one when the request is sent.
+struct PublicResolver {
+    /// The hosts of the configured proxies, which are allowed to be internal
+    proxy_hosts: Vec<String>,
+}
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let is_proxy = self.proxy_hosts.iter().any(|host| host == name.as_str());
+        Box::pin(async move {
+            // The port gets replaced with the one from the URL when connecting
+            let addresses: Vec<SocketAddr> = lookup_host((name.as_str(), 0)).await?.collect();
+            if !is_proxy {
+                if let Some(address) = addresses
+                    .iter()
+                    .find(|address| is_internal_ip(address.ip()))
+                {
+                    return Err(format!("host points to internal address {}", address.ip()).into());
                 }
-            } else {
-                warn!("Failed to fetch metadata for '{}'", &url);
             }
+            let addresses: reqwest::dns::Addrs = Box::new(addresses.into_iter());
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(addresses)
+        })
+    }
+}
+
+/// Checks if `host` is `domain` or one of its subdomains.
+fn host_matches_domain(host: &str, domain: &str) -> bool {
+    let domain = domain.trim_start_matches('.').to_lowercase();
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+/// Checks the embed domain allowlist and blocklist to see if links to `host` can be embedded.
+///
+/// Anything on the blocklist is always skipped, even if it's also on the allowlist.
+fn is_domain_allowed(host: &str, config: &Config) -> bool {
+    if config
+        .embed_domain_blocklist
+        .iter()
+        .any(|domain| host_matches_domain(host, domain))
+    {
+        return false;
+    }
+
+    match &config.embed_domain_allowlist {
+        Some(allowlist) => allowlist
+            .iter()
+            .any(|domain| host_matches_domain(host, domain)),
+        None => true,
+    }
+}
+
+/// Reserves the next fetch from `domain`, returning how long to wait before making it.
+///
+/// Fetches from the same domain are spaced at least `cooldown` apart, so a burst of links to one
+/// site queue up behind each other instead of all hitting it at once. If the wait would be longer
+/// than `max_wait`, nothing is reserved and `None` is returned.
+fn reserve_fetch(domain: &str, cooldown: Duration, max_wait: Duration) -> Option<Duration> {
+    let mut next_fetch = NEXT_FETCH.lock().unwrap();
+    let now = Instant::now();
+
+    // Forget about domains that can be fetched from straight away so the map doesn't grow forever
+    next_fetch.retain(|_, next| *next > now);
+    let start = next_fetch.get(domain).copied().unwrap_or(now);
+    let wait = start.saturating_duration_since(now);
+    if wait > max_wait {
+        return None;
+    }
+
+    next_fetch.insert(domain.to_string(), start + cooldown);
+    Some(wait)
+}
+
+/// Looks up the cached embed for `url`, if it's younger than `ttl`.
+///
+/// Pages we couldn't get any metadata out of are cached too, as `Some(None)`.
+fn cached_embed(url: &str, ttl: Duration) -> Option<Option<Embed>> {
+    EMBED_CACHE.lock().unwrap().get(url, ttl).cloned()
+}
+
+/// Stores the embed for `url` in the cache.
+fn cache_embed(url: &str, embed: Option<Embed>, ttl: Duration) {
+    EMBED_CACHE
+        .lock()
+        .unwrap()
+        .insert(url.to_string(), embed, ttl);
+}
+
+/// Builds the HTTP client shared by embeds, commands and feeds.
+///
+/// This is built once at startup and shared between all messages so connections get reused.
+/// Redirects are followed up to the configured limit, as long as they don't go from HTTPS to HTTP
+/// or straight to an internal IP address.
+pub fn build_http_client(config: &Config) -> reqwest::Result<reqwest::Client> {
+    let max_redirects = config.embed_max_redirects;
+    client_builder(config)?
+        .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            let Some(previous) = attempt.previous().last() else {
+                return attempt.follow();
+            };
+            match redirect_error(
+                previous,
+                attempt.url(),
+                attempt.previous().len(),
+                max_redirects,
+            ) {
+                Some(error) => attempt.error(error),
+                None => attempt.follow(),
+            }
+        }))
+        .build()
+}
+
+/// Builds the HTTP client that pages linked in chat are downloaded with.
+///
+/// This one doesn't follow redirects itself, [`fetch_page`] does that so it can check where each
+/// one goes before following it. Hostnames are resolved with a [`PublicResolver`], so the
+/// addresses it connects to are the ones that were checked.
+pub fn build_page_client(config: &Config) -> reqwest::Result<reqwest::Client> {
+    let proxy_hosts = [
+        config.socks_proxy.clone(),
+        proxy_setting(&config.http_proxy, "HTTP_PROXY"),
+        proxy_setting(&config.https_proxy, "HTTPS_PROXY"),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(|proxy| Some(Url::parse(&proxy).ok()?.host_str()?.to_string()))
+    .collect();
+
+    client_builder(config)?
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver { proxy_hosts }))
+        .build()
+}
+
+/// Starts building an HTTP client with the user agent, timeout and proxies from the [`Config`].
+fn client_builder(config: &Config) -> reqwest::Result<reqwest::ClientBuilder> {
+    let builder = reqwest::Client::builder()
+        .user_agent(
+            config
+                .embed_user_agent
+                .as_deref()
+                .unwrap_or(DEFAULT_USER_AGENT),
+        )
+        .timeout(Duration::from_secs(config.embed_timeout_secs))
+        // Some servers compress no matter what we ask for, and the parser needs the plain HTML
+        .gzip(true)
+        .brotli(true)
+        .deflate(true);
+
+    // A SOCKS proxy takes everything, for routing all fetches over something like Tor. The
+    // private address checks in `fetch_page` still run first, but they go by what this machine
+    // resolves, so with socks5h:// the proxy itself has to be trusted not to reach internal hosts.
+    if let Some(socks_proxy) = &config.socks_proxy {
+        return Ok(builder.proxy(reqwest::Proxy::all(socks_proxy)?));
+    }
+
+    // Setting any proxy turns off reqwest's own environment lookup, so the variables are read here
+    // for whichever one isn't in the config, NO_PROXY included. Credentials in the proxy URL are
+    // used to log into it.
+    let mut builder = builder;
+    if let Some(http_proxy) = proxy_setting(&config.http_proxy, "HTTP_PROXY") {
+        let proxy = reqwest::Proxy::http(http_proxy)?.no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    if let Some(https_proxy) = proxy_setting(&config.https_proxy, "HTTPS_PROXY") {
+        let proxy = reqwest::Proxy::https(https_proxy)?.no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder)
+}
+
+/// Checks if a redirect from `from` to `to` shouldn't be followed, and says why.
+///
+/// `redirects` is how many redirects were already followed to get to `from`. Hostnames aren't
+/// resolved here, [`fetch_page`] does that with [`ensure_public_host`] before following one.
+fn redirect_error(from: &Url, to: &Url, redirects: usize, max_redirects: usize) -> Option<String> {
+    if redirects >= max_redirects {
+        return Some(format!("gave up after {} redirects", redirects));
+    }
+    if !matches!(to.scheme(), "http" | "https") {
+        return Some(format!("won't redirect to a {} URL", to.scheme()));
+    }
+    if from.scheme() == "https" && to.scheme() == "http" {
+        return Some("won't redirect from HTTPS to HTTP".to_string());
+    }
+    let internal_ip = match to.host() {
+        Some(Host::Ipv4(ip)) => is_internal_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => is_internal_ip(IpAddr::V6(ip)),
+        _ => false,
+    };
+    if internal_ip {
+        return Some("won't redirect to an internal address".to_string());
+    }
+    None
+}
+
+/// Gets a proxy URL from the config, or from the environment variable `name` if it isn't set.
+///
+/// Both the uppercase and lowercase versions of the variable are checked, like curl does.
+fn proxy_setting(configured: &Option<String>, name: &str) -> Option<String> {
+    configured
+        .clone()
+        .or_else(|| std::env::var(name).ok())
+        .or_else(|| std::env::var(name.to_lowercase()).ok())
+        .filter(|proxy| !proxy.trim().is_empty())
+}
+
+/// The future returned by [`MetadataFetcher::fetch`].
+pub type FetchFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Page>> + Send + 'a>>;
+
+/// The future returned by [`MetadataFetcher::fetch_image`].
+pub type ImageFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Image>> + Send + 'a>>;
+
+/// The future returned by [`MetadataFetcher::fetch_api`].
+pub type ApiFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + 'a>>;
+
+/// A request to a site's API, for links whose embed comes from the API instead of the page.
+pub struct ApiRequest {
+    /// The API endpoint, with any query parameters
+    pub url: Url,
+    /// The `Accept` header to send (e.g. "application/json")
+    pub accept: &'static str,
+    /// A token to authenticate with, if the API needs one
+    pub bearer_token: Option<String>,
+}
+
+/// A page downloaded from a link, ready to be scraped.
+pub struct Page {
+    /// The URL the page was fetched from in the end, after following redirects
+    pub url: String,
+    /// The HTML of the page
+    pub html: String,
+}
+
+/// An image downloaded from a link, ready to be uploaded.
+pub struct Image {
+    /// The contents of the image file
+    pub data: Vec<u8>,
+    /// What kind of image it is (e.g. "image/png")
+    pub content_type: mime::Mime,
+}
+
+/// Fetches the pages that embeds are scraped from.
+///
+/// The embed handler only goes out to the network through this, so it can be given one that
+/// returns canned HTML and API responses instead.
+pub trait MetadataFetcher: Send + Sync {
+    /// Fetches the page at `url`.
+    fn fetch<'a>(&'a self, url: &'a str) -> FetchFuture<'a>;
+
+    /// Fetches the image at `url`, for links straight to an image.
+    fn fetch_image<'a>(&'a self, url: &'a str) -> ImageFuture<'a>;
+
+    /// Fetches the body of an API response, for links like YouTube videos and GitHub issues.
+    fn fetch_api(&self, request: ApiRequest) -> ApiFuture<'_>;
+}
+
+/// The [`MetadataFetcher`] frogbot runs with, which downloads pages over HTTP.
+pub struct HttpFetcher {
+    /// The client pages are downloaded with, from [`build_page_client`]
+    client: reqwest::Client,
+    /// The client APIs are called with, from [`build_http_client`]
+    api_client: reqwest::Client,
+    /// frogbot's configuration, for the fetch limits
+    config: Arc<Config>,
+}
+
+impl HttpFetcher {
+    /// Creates a new [`HttpFetcher`].
+    pub fn new(
+        client: reqwest::Client,
+        api_client: reqwest::Client,
+        config: Arc<Config>,
+    ) -> HttpFetcher {
+        HttpFetcher {
+            client,
+            api_client,
+            config,
         }
+    }
+}
+
+impl MetadataFetcher for HttpFetcher {
+    fn fetch<'a>(&'a self, url: &'a str) -> FetchFuture<'a> {
+        Box::pin(fetch_page(&self.client, url, &self.config))
+    }
+
+    fn fetch_image<'a>(&'a self, url: &'a str) -> ImageFuture<'a> {
+        Box::pin(fetch_image(&self.client, url, &self.config))
+    }
+
+    fn fetch_api(&self, request: ApiRequest) -> ApiFuture<'_> {
+        Box::pin(fetch_api(&self.api_client, request))
+    }
+}
+
+/// Downloads the page at `url` so it can be turned into an [`Embed`].
+///
+/// Anything that isn't HTML is skipped, as is any page whose `Content-Length` is over the
+/// configured maximum body size. Bodies without a `Content-Length` are streamed and cut off once
+/// they hit the limit, since the metadata we care about lives in the `<head>` anyway.
+#[instrument(level = "debug", skip(reqwest_client, config))]
+async fn fetch_page(
+    reqwest_client: &reqwest::Client,
+    url: &str,
+    config: &Config,
+) -> anyhow::Result<Page> {
+    let res = send_request(reqwest_client, url, config).await?;
+    let final_url = res.url().to_string();
+
+    // Don't download and parse things that aren't web pages (videos, JSON, etc.)
+    if !is_html_response(&res) {
+        bail!("response isn't HTML");
+    }
+
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(str::to_string);
+    let (body, _) = read_body(res, config.embed_max_body_size).await?;
+
+    // Plenty of pages still aren't UTF-8, so decode them properly instead of getting mojibake
+    let encoding = detect_encoding(content_type.as_deref(), &body);
+    let (html, _, _) = encoding.decode(&body);
+    Ok(Page {
+        url: final_url,
+        html: html.into_owned(),
+    })
+}
+
+/// Downloads the image at `url`, so it can be uploaded to the homeserver.
+///
+/// Only PNG, JPEG, GIF and WebP images are downloaded, and only if they fit in the configured
+/// maximum image size. Unlike pages, images that go over the limit are skipped entirely, since
+/// half an image is no use to anyone.
+#[instrument(level = "debug", skip(reqwest_client, config))]
+async fn fetch_image(
+    reqwest_client: &reqwest::Client,
+    url: &str,
+    config: &Config,
+) -> anyhow::Result<Image> {
+    let res = send_request(reqwest_client, url, config).await?;
+
+    let content_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok());
+    // SVGs can carry scripts, so they're left out along with everything that isn't an image
+    let Some(content_type) = content_type.filter(|content_type| {
+        matches!(
+            content_type.essence_str(),
+            "image/png" | "image/jpeg" | "image/gif" | "image/webp"
+        )
+    }) else {
+        bail!("response isn't a supported image");
     };
+
+    let (data, truncated) = read_body(res, config.embed_image_max_size).await?;
+    if truncated {
+        bail!(
+            "image is over the limit of {} bytes",
+            config.embed_image_max_size
+        );
+    }
+    Ok(Image { data, content_type })
+}
+
+/// Calls a site's API, returning the body of the response.
+///
+/// These only ever go to fixed API hosts, so they don't need the checks links posted in chat get.
+async fn fetch_api(api_client: &reqwest::Client, request: ApiRequest) -> anyhow::Result<String> {
+    let mut builder = api_client
+        .get(request.url)
+        .header(reqwest::header::ACCEPT, request.accept);
+    if let Some(bearer_token) = &request.bearer_token {
+        builder = builder.bearer_auth(bearer_token);
+    }
+    Ok(builder.send().await?.error_for_status()?.text().await?)
+}
+
+/// Sends a GET request for a link posted in chat, following redirects.
+///
+/// The host is checked before every request, so a public page can't bounce us to an internal
+/// one, and links to a domain fetched from too recently wait for its cooldown to end.
+async fn send_request(
+    reqwest_client: &reqwest::Client,
+    url: &str,
+    config: &Config,
+) -> anyhow::Result<reqwest::Response> {
+    let mut url = Url::parse(url)?;
+    ensure_public_host(&url).await?;
+
+    // Don't hammer a site just because a room is full of links to it, but don't wait around for
+    // longer than the request itself is allowed to take either
+    let cooldown = Duration::from_millis(config.embed_domain_cooldown_ms);
+    let max_wait = Duration::from_secs(config.embed_timeout_secs);
+    let Some(wait) = reserve_fetch(url.host_str().unwrap_or_default(), cooldown, max_wait) else {
+        bail!("too many fetches from this domain are waiting already");
+    };
+    if !wait.is_zero() {
+        debug!("Waiting {:?} for the domain cooldown of: '{}'", wait, url);
+        tokio::time::sleep(wait).await;
+    }
+
+    // Follow redirects by hand, so each one can be checked before it's followed
+    let mut redirects = 0;
+    loop {
+        let res = reqwest_client.get(url.clone()).send().await?;
+        let location = res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok());
+        let Some(location) = location.filter(|_| res.status().is_redirection()) else {
+            return Ok(res);
+        };
+
+        let next = url.join(location)?;
+        if let Some(error) = redirect_error(&url, &next, redirects, config.embed_max_redirects) {
+            bail!("{}", error);
+        }
+        ensure_public_host(&next).await?;
+        debug!("Following redirect from: '{}' to: '{}'", url, next);
+        redirects += 1;
+        url = next;
+    }
+}
+
+/// Reads up to `limit` bytes of a response body, returning whether it had to be cut off.
+///
+/// Responses whose `Content-Length` is already over the limit aren't downloaded at all.
+pub async fn read_body(
+    mut res: reqwest::Response,
+    limit: usize,
+) -> anyhow::Result<(Vec<u8>, bool)> {
+    // Don't bother downloading bodies that already tell us they're too big
+    if let Some(length) = res.content_length() {
+        if length > limit as u64 {
+            bail!(
+                "response is {} bytes, which is over the limit of {} bytes",
+                length,
+                limit
+            );
+        }
+    }
+
+    // Chunks come out already decompressed, so the size limit applies to the decoded body
+    let mut body: Vec<u8> = vec![];
+    while let Some(chunk) = res.chunk().await? {
+        let remaining = limit - body.len();
+        if chunk.len() > remaining {
+            debug!(
+                "Body for URL: '{}' hit the size limit, truncating",
+                res.url()
+            );
+            body.extend_from_slice(&chunk[..remaining]);
+            return Ok((body, true));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok((body, false))
+}
+
+/// Checks if `url` points to a YouTube video.
+fn is_youtube_video(url: &Url) -> bool {
+    match url.host_str() {
+        Some("youtu.be") => url.path().len() > 1,
+        Some("youtube.com" | "www.youtube.com" | "m.youtube.com") => url.path() == "/watch",
+        _ => false,
+    }
+}
+
+/// Builds an [`Embed`] for a YouTube video from YouTube's oEmbed endpoint.
+///
+/// The video pages themselves are mostly JavaScript, so scraping them gives pretty useless embeds.
+async fn fetch_youtube_embed(fetcher: &dyn MetadataFetcher, url: &Url) -> anyhow::Result<Embed> {
+    let mut oembed_url = Url::parse("https://www.youtube.com/oembed")?;
+    oembed_url
+        .query_pairs_mut()
+        .append_pair("url", url.as_str())
+        .append_pair("format", "json");
+    let response = fetcher
+        .fetch_api(ApiRequest {
+            url: oembed_url,
+            accept: "application/json",
+            bearer_token: None,
+        })
+        .await?;
+    let oembed: OEmbed = serde_json::from_str(&response)?;
+
+    let Some(title) = oembed.title.filter(|title| !title.is_empty()) else {
+        bail!("oEmbed response has no title");
+    };
+    let description = oembed
+        .author_name
+        .map(|author| format!("by {}", author))
+        .unwrap_or_default();
+
+    let mut embed = Embed::new(title, description);
+    embed.image = oembed.thumbnail_url;
+    Ok(embed)
+}
+
+/// An issue or pull request on GitHub, pointed to by a link.
+struct GitHubIssue {
+    /// The user or organisation that owns the repository
+    owner: String,
+    /// The name of the repository
+    repo: String,
+    /// The issue or pull request number
+    number: u64,
+}
+
+impl GitHubIssue {
+    /// Gets the issue or pull request `url` points to, if it's a GitHub issue or pull request.
+    fn from_url(url: &Url) -> Option<GitHubIssue> {
+        if !matches!(url.host_str(), Some("github.com" | "www.github.com")) {
+            return None;
+        }
+        let mut segments = url.path_segments()?;
+        let (owner, repo, kind, number) = (
+            segments.next()?,
+            segments.next()?,
+            segments.next()?,
+            segments.next()?,
+        );
+        if !matches!(kind, "issues" | "pull") {
+            return None;
+        }
+        Some(GitHubIssue {
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            number: number.parse().ok()?,
+        })
+    }
+}
+
+/// The parts of a GitHub issue API response used to build an [`Embed`].
+#[derive(Deserialize)]
+struct GitHubIssueResponse {
+    title: String,
+    state: String,
+    user: GitHubUser,
+    #[serde(default)]
+    labels: Vec<serde_json::Value>,
+    pull_request: Option<GitHubPullRequest>,
+}
+
+/// The author of a GitHub issue.
+#[derive(Deserialize)]
+struct GitHubUser {
+    login: String,
+}
+
+/// The extra details GitHub includes when an issue is a pull request.
+#[derive(Deserialize)]
+struct GitHubPullRequest {
+    merged_at: Option<String>,
+}
+
+/// Builds an [`Embed`] for a GitHub issue or pull request from the GitHub API.
+///
+/// The pages themselves only give a generic title, while the API has the state and author too.
+async fn fetch_github_embed(
+    fetcher: &dyn MetadataFetcher,
+    issue: &GitHubIssue,
+    config: &Config,
+) -> anyhow::Result<Embed> {
+    // The API treats pull requests as issues too, so this works for both
+    let mut url = Url::parse("https://api.github.com/repos")?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("GitHub API URL can't have a path"))?
+        .push(&issue.owner)
+        .push(&issue.repo)
+        .push("issues")
+        .push(&issue.number.to_string());
+    let response = fetcher
+        .fetch_api(ApiRequest {
+            url,
+            accept: "application/vnd.github+json",
+            bearer_token: config.github_token.clone(),
+        })
+        .await?;
+    let response: GitHubIssueResponse = serde_json::from_str(&response)?;
+
+    let (kind, state) = match &response.pull_request {
+        Some(pull_request) if pull_request.merged_at.is_some() => ("Pull request", "merged"),
+        Some(_) => ("Pull request", response.state.as_str()),
+        None => ("Issue", response.state.as_str()),
+    };
+    let mut description = format!(
+        "{} #{} in {}/{} · {} · by {}",
+        kind, issue.number, issue.owner, issue.repo, state, response.user.login
+    );
+    match response.labels.len() {
+        0 => {}
+        1 => description.push_str(" · 1 label"),
+        labels => description.push_str(&format!(" · {} labels", labels)),
+    }
+
+    Ok(Embed::new(response.title, description))
+}
+
+/// Records the URLs found in a message, returning only the ones it didn't already have.
+///
+/// Edits point back at the original message, so this is what stops an edit from embedding the
+/// links that were there all along.
+fn new_message_urls(event_id: &EventId, urls: Vec<String>) -> Vec<String> {
+    // The oldest message is forgotten once we're tracking too many
+    let mut message_urls = MESSAGE_URLS.lock().unwrap();
+    let seen_urls = message_urls.get_or_insert_with(event_id.to_owned(), HashSet::new);
+    urls.into_iter()
+        .filter(|url| seen_urls.insert(url.clone()))
+        .collect()
+}
+
+/// Checks if `url` looks like a link straight to an image, going by its file extension.
+fn is_image_url(url: &str) -> bool {
+    let Ok(url) = Url::parse(url) else {
+        return false;
+    };
+    let path = url.path().to_lowercase();
+    [".png", ".jpg", ".jpeg", ".gif", ".webp"]
+        .iter()
+        .any(|extension| path.ends_with(extension))
+}
+
+/// Downloads the image at `url`, if its domain is allowed to be embedded.
+async fn get_image(
+    fetcher: &dyn MetadataFetcher,
+    url: &str,
+    config: &Config,
+) -> anyhow::Result<Image> {
+    let parsed_url = Url::parse(url)?;
+    if !is_domain_allowed(parsed_url.host_str().unwrap_or_default(), config) {
+        bail!("domain isn't allowed to be embedded");
+    }
+
+    let _permit = fetch_permit(config).await?;
+    fetcher.fetch_image(url).await
+}
+
+/// Waits for a free slot to fetch a link in.
+///
+/// This keeps a burst of links from opening an unbounded number of connections.
+async fn fetch_permit(config: &Config) -> anyhow::Result<SemaphorePermit<'static>> {
+    Ok(FETCH_PERMITS
+        .get_or_init(|| Semaphore::new(config.embed_max_concurrent_fetches))
+        .acquire()
+        .await?)
+}
+
+/// Gets the [`Embed`] for `url`, reusing the cached one if it was fetched recently.
+#[instrument(level = "debug", skip(fetcher, config))]
+pub async fn get_embed(
+    fetcher: &dyn MetadataFetcher,
+    url: &str,
+    config: &Config,
+) -> anyhow::Result<Option<Embed>> {
+    let parsed_url = Url::parse(url)?;
+    if !is_domain_allowed(parsed_url.host_str().unwrap_or_default(), config) {
+        bail!("domain isn't allowed to be embedded");
+    }
+
+    let ttl = Duration::from_secs(config.embed_cache_ttl_secs);
+    if let Some(embed) = cached_embed(url, ttl) {
+        debug!("Using cached embed for URL: '{}'", url);
+        return Ok(embed);
+    }
+
+    // Wait for a free slot before touching the network
+    let _permit = fetch_permit(config).await?;
+
+    if is_youtube_video(&parsed_url) {
+        match fetch_youtube_embed(fetcher, &parsed_url).await {
+            Ok(embed) => {
+                cache_embed(url, Some(embed.clone()), ttl);
+                return Ok(Some(embed));
+            }
+            Err(e) => warn!(
+                "Failed to get oEmbed for URL: '{}', falling back to the page: {}",
+                url, e
+            ),
+        }
+    }
+
+    if let Some(issue) = GitHubIssue::from_url(&parsed_url) {
+        match fetch_github_embed(fetcher, &issue, config).await {
+            Ok(embed) => {
+                cache_embed(url, Some(embed.clone()), ttl);
+                return Ok(Some(embed));
+            }
+            Err(e) => warn!(
+                "Failed to get GitHub issue for URL: '{}', falling back to the page: {}",
+                url, e
+            ),
+        }
+    }
+
+    let page = fetcher.fetch(url).await?;
+    // beware, dirty HTML parsing code
+    let mut embed = match parse_metadata(&page.html) {
+        Ok(embed) => Some(embed),
+        Err(e) => {
+            info!("Couldn't get metadata for URL: '{}': {}", url, e);
+            None
+        }
+    };
+
+    // Cache under the canonical URL too, so the clean version of a messy link hits the cache
+    if let Some(embed) = &mut embed {
+        embed.canonical_url = embed
+            .canonical_url
+            .as_deref()
+            .and_then(|canonical_url| same_host_canonical_url(&page.url, canonical_url));
+        if let Some(canonical_url) = &embed.canonical_url {
+            cache_embed(canonical_url, Some(embed.clone()), ttl);
+        }
+    }
+    cache_embed(url, embed.clone(), ttl);
+    Ok(embed)
+}
+
+/// Shows frogbot as typing in a room until it's dropped.
+///
+/// Clearing the notice on drop means it can't get stuck on, however the embed handler returns.
+struct TypingGuard {
+    /// The room frogbot is typing in
+    room: Joined,
+}
+
+impl TypingGuard {
+    /// Starts showing frogbot as typing in `room`.
+    async fn start(room: &Joined) -> Option<TypingGuard> {
+        if let Err(e) = room.typing_notice(true).await {
+            warn!("Failed to send typing notice: {}", e);
+            return None;
+        }
+        Some(TypingGuard { room: room.clone() })
+    }
+}
+
+impl Drop for TypingGuard {
+    fn drop(&mut self) {
+        let room = self.room.clone();
+        tokio::spawn(async move {
+            if let Err(e) = room.typing_notice(false).await {
+                warn!("Failed to clear typing notice: {}", e);
+            }
+        });
+    }
+}
+
+/// Checks messages for valid links and generates embeds if found
+#[instrument(level = "debug", skip_all, fields(room_id = %room.room_id(), sender = %event.sender))]
+pub async fn embed_handler(
+    event: OriginalSyncRoomMessageEvent,
+    room: Room,
+    client: Client,
+    Ctx(state): Ctx<BotState>,
+) {
+    if let Room::Joined(room) = room {
+        let config = state.config.get();
+        let BotState {
+            fetcher,
+            embed_settings,
+            embed_replies,
+            ..
+        } = state;
+        // If the sender ID matches our client, ignore the message
+        // We don't want to reply to ourselves
+        let client_user_id = client.user_id().unwrap();
+        if event.sender == client_user_id {
+            return;
+        }
+
+        // Other link unfurling bots would end up embedding each other's embeds forever
+        if config.is_ignored(&event.sender) {
+            return;
+        }
+
+        // Everything else is read, even when it doesn't get embeds, since it could be a command
+        receipts::mark_handled(&config, room.room_id(), &event.event_id);
+
+        if !embed_settings
+            .lock()
+            .unwrap()
+            .embeds_enabled(room.room_id(), &config)
+        {
+            return;
+        }
+
+        let full_reply_event = event.clone().into_full_event(room.room_id().to_owned());
+
+        if embed_settings.lock().unwrap().is_opted_out(&event.sender) {
+            return;
+        }
+
+        let is_reply = matches!(&event.content.relates_to, Some(Relation::Reply { .. }));
+
+        // Edits carry the new version of the message, and point back at the original one
+        let (msgtype, source_event_id) = match event.content.relates_to {
+            Some(Relation::Replacement(replacement)) => {
+                (replacement.new_content.msgtype, replacement.event_id)
+            }
+            _ => (event.content.msgtype, event.event_id.clone()),
+        };
+
+        let Some((body, formatted)) = message_text(msgtype) else {
+            return;
+        };
+
+        // `!embed` already replies with the embed it was asked for
+        let is_embed_command = parse_command(&body, &config.command_prefix)
+            .is_some_and(|invocation| invocation.name == "embed");
+        if is_embed_command {
+            return;
+        }
+
+        let urls = message_urls(&body, formatted, is_reply, &config);
+        if urls.is_empty() {
+            return;
+        }
+        // Markdown links come with their own text, which beats an embed with no title at all
+        let link_texts: HashMap<String, String> = get_markdown_links(&body)
+            .into_iter()
+            .map(|(text, url)| {
+                (
+                    strip_tracking_params(url, &config.embed_tracking_params),
+                    text.to_string(),
+                )
+            })
+            .collect();
+
+        // Slow pages would hold up every other event if they were fetched here, so the fetching
+        // and replying happen in their own task and the handler returns straight away. The slot
+        // is taken before the links are remembered, so an edit can still embed a skipped message.
+        let Some(permit) = embed_task_permit() else {
+            warn!(
+                "Too many embeds in progress, skipping message: '{}'",
+                source_event_id
+            );
+            return;
+        };
+
+        // The same link usually shows up in both the plain and formatted body, and edits repeat
+        // the links that were already there, so only keep the ones we haven't seen yet
+        let urls = new_message_urls(&source_event_id, urls);
+
+        // Don't let someone bury the room in embeds by posting a pile of links
+        let (urls, skipped_urls) = limit_urls(urls, config.max_embeds_per_message);
+        if urls.is_empty() && skipped_urls == 0 {
+            return;
+        }
+
+        let job = EmbedJob {
+            client,
+            room,
+            event: full_reply_event,
+            source_event_id,
+            urls,
+            link_texts,
+            skipped_urls,
+        };
+        // The task outlives the handler, so it carries the handler's span along with it
+        tokio::spawn(
+            async move {
+                send_embeds(job, config, fetcher, embed_replies).await;
+                drop(permit);
+            }
+            .in_current_span(),
+        );
+    };
+}
+
+/// Keeps the first `max` of a message's links, returning them along with how many were left out.
+fn limit_urls(mut urls: Vec<String>, max: usize) -> (Vec<String>, usize) {
+    let skipped_urls = urls.len().saturating_sub(max);
+    urls.truncate(max);
+    (urls, skipped_urls)
+}
+
+/// The note sent when some of a message's links were left out for going over the limit.
+fn skipped_urls_note(skipped_urls: usize) -> String {
+    format!("(+{} more links not embedded)", skipped_urls)
+}
+
+/// Takes one of the slots for work running in the background, like fetching embeds, if there's
+/// one free.
+///
+/// The slot is given back when the permit is dropped.
+pub fn embed_task_permit() -> Option<OwnedSemaphorePermit> {
+    EMBED_TASKS.clone().try_acquire_owned().ok()
+}
+
+/// The links from a message that are waiting to be embedded.
+struct EmbedJob {
+    /// The client images get uploaded with
+    client: Client,
+    /// The room the message was sent in
+    room: Joined,
+    /// The message the embeds reply to
+    event: OriginalRoomMessageEvent,
+    /// The ID of the message the links came from, the original one if this was an edit
+    source_event_id: OwnedEventId,
+    /// The links to embed, in the order they were posted
+    urls: Vec<String>,
+    /// The text of links that were written in markdown, by URL
+    link_texts: HashMap<String, String>,
+    /// How many links were left out for going over the limit
+    skipped_urls: usize,
+}
+
+/// What was fetched for a link.
+enum Fetched {
+    /// An embed for a page
+    Embed(anyhow::Result<Option<Embed>>),
+    /// An image, for links straight to one
+    Image(anyhow::Result<Image>),
+}
+
+/// Fetches what to embed for each of a message's links.
+///
+/// The pages are fetched concurrently, but handed back in the order they were posted.
+fn fetch_links<'a>(
+    urls: &'a [String],
+    fetcher: &'a dyn MetadataFetcher,
+    config: &'a Config,
+) -> impl Stream<Item = (&'a str, Fetched)> + Unpin + 'a {
+    stream::iter(urls.iter().map(String::as_str))
+        .map(move |url| async move {
+            if config.embed_images && is_image_url(url) {
+                return (url, Fetched::Image(get_image(fetcher, url, config).await));
+            }
+            let embed = get_embed(fetcher, url, config).await;
+            (url, Fetched::Embed(embed))
+        })
+        .buffered(MAX_CONCURRENT_FETCHES)
+}
+
+/// Fetches the embeds for a message's links and sends them as replies.
+async fn send_embeds(
+    job: EmbedJob,
+    config: Arc<Config>,
+    fetcher: Arc<dyn MetadataFetcher>,
+    embed_replies: Arc<Mutex<EmbedReplies>>,
+) {
+    // Slow pages can take a while, so let people know something is happening
+    let _typing = if config.embed_typing_notice && !job.urls.is_empty() {
+        TypingGuard::start(&job.room).await
+    } else {
+        None
+    };
+
+    let fetcher = &*fetcher;
+    let config = &config;
+    let mut embeds = fetch_links(&job.urls, fetcher, config);
+
+    let mut embedded_urls = HashSet::new();
+    while let Some((url, fetched)) = embeds.next().await {
+        let metadata = match fetched {
+            Fetched::Embed(metadata) => metadata,
+            Fetched::Image(image) => {
+                send_image(&job, url, image, config, &embed_replies).await;
+                continue;
+            }
+        };
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                metrics::EMBED_FETCH_FAILURES.increment();
+                warn!("Failed to fetch metadata for '{}': {}", &url, e);
+                continue;
+            }
+        };
+
+        // Build and send our message reply
+        if let Some(mut embed) = metadata {
+            if embed.title.is_empty() {
+                if let Some(text) = job.link_texts.get(url) {
+                    embed.title = text.clone();
+                }
+            }
+
+            // Different links to the same page only get one embed
+            let page_url = embed.canonical_url.as_deref().unwrap_or(url);
+            if !embedded_urls.insert(page_url.to_string()) {
+                debug!("Already embedded '{}', skipping URL: '{}'", page_url, &url);
+                continue;
+            }
+
+            upload_thumbnail(&mut embed, url, &job.client, fetcher, config).await;
+            let bot_reply = embed.to_message_content(config, &job.event);
+
+            // Finally send the reply to the room
+            info!("Sending embed for URL: '{}'", &url);
+            match retry_rate_limited(|| job.room.send(bot_reply.clone(), None)).await {
+                Ok(response) => {
+                    metrics::EMBEDS_SENT.increment();
+                    record_reply(
+                        &embed_replies,
+                        &job.source_event_id,
+                        response.event_id,
+                        config,
+                    )
+                }
+                Err(_) => warn!("Failed to send embed for URL: '{}'", &url),
+            }
+        // If we didn't get any metadata send a generic "No metadata" response, if wanted
+        } else if let Some(bot_reply) = no_metadata_reply(config, &job.event) {
+            // Send the reply to the room
+            info!("Sending 'No metadata' embed for URL: '{}'", &url);
+            match retry_rate_limited(|| job.room.send(bot_reply.clone(), None)).await {
+                Ok(response) => record_reply(
+                    &embed_replies,
+                    &job.source_event_id,
+                    response.event_id,
+                    config,
+                ),
+                Err(_) => warn!("Failed to send embed for URL: '{}'", &url),
+            }
+        } else {
+            info!("No metadata for URL: '{}', not replying", &url);
+        }
+    }
+
+    if job.skipped_urls > 0 {
+        let note = skipped_urls_note(job.skipped_urls);
+        let bot_reply = reply_to_message(RoomMessageEventContent::text_plain(note), &job.event);
+        info!("Skipped embedding {} URLs", job.skipped_urls);
+        match retry_rate_limited(|| job.room.send(bot_reply.clone(), None)).await {
+            Ok(response) => record_reply(
+                &embed_replies,
+                &job.source_event_id,
+                response.event_id,
+                config,
+            ),
+            Err(_) => warn!("Failed to send note about skipped URLs"),
+        }
+    }
+}
+
+/// Uploads an embed's thumbnail to the homeserver, and points the embed at the uploaded copy.
+///
+/// Clients only show `mxc://` images inline, and loading one straight from the page would tell
+/// the linked site the IP address of everyone reading the room. The thumbnail is fetched like any
+/// other image link, and if that or the upload fails the embed just goes without one.
+pub async fn upload_thumbnail(
+    embed: &mut Embed,
+    page_url: &str,
+    client: &Client,
+    fetcher: &dyn MetadataFetcher,
+    config: &Config,
+) {
+    let Some(image_url) = embed
+        .image
+        .take()
+        .and_then(|image| resolve_url(page_url, &image))
+    else {
+        return;
+    };
+
+    let image = match get_image(fetcher, &image_url, config).await {
+        Ok(image) => image,
+        Err(e) => {
+            debug!("Failed to fetch thumbnail: '{}': {}", image_url, e);
+            return;
+        }
+    };
+    match client.media().upload(&image.content_type, image.data).await {
+        Ok(upload) => embed.image = Some(upload.content_uri.to_string()),
+        Err(e) => warn!("Failed to upload thumbnail: '{}': {}", image_url, e),
+    }
+}
+
+/// Uploads an image that was linked to and sends it as a reply, so it shows up inline even in
+/// clients that don't preview links.
+async fn send_image(
+    job: &EmbedJob,
+    url: &str,
+    image: anyhow::Result<Image>,
+    config: &Config,
+    embed_replies: &Mutex<EmbedReplies>,
+) {
+    let image = match image {
+        Ok(image) => image,
+        Err(e) => {
+            metrics::EMBED_FETCH_FAILURES.increment();
+            warn!("Failed to fetch image for '{}': {}", url, e);
+            return;
+        }
+    };
+
+    let size = image.data.len();
+    let upload = match job
+        .client
+        .media()
+        .upload(&image.content_type, image.data)
+        .await
+    {
+        Ok(upload) => upload,
+        Err(e) => {
+            warn!("Failed to upload image for '{}': {}", url, e);
+            return;
+        }
+    };
+
+    let mut info = ImageInfo::new();
+    info.mimetype = Some(image.content_type.to_string());
+    info.size = UInt::new(size as u64);
+    let file_name = Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.path_segments()
+                .and_then(|mut segments| segments.next_back().map(str::to_string))
+        })
+        .filter(|file_name| !file_name.is_empty())
+        .unwrap_or_else(|| "image".to_string());
+    let content = RoomMessageEventContent::new(MessageType::Image(
+        ImageMessageEventContent::plain(file_name, upload.content_uri, Some(Box::new(info))),
+    ));
+    let bot_reply = reply_to_message(content, &job.event);
+
+    info!("Sending image for URL: '{}'", url);
+    match retry_rate_limited(|| job.room.send(bot_reply.clone(), None)).await {
+        Ok(response) => {
+            metrics::EMBEDS_SENT.increment();
+            record_reply(
+                embed_replies,
+                &job.source_event_id,
+                response.event_id,
+                config,
+            )
+        }
+        Err(_) => warn!("Failed to send image for URL: '{}'", url),
+    }
+}
+
+/// Remembers a reply frogbot sent for a message, saving it to the store.
+fn record_reply(
+    embed_replies: &Mutex<EmbedReplies>,
+    source_id: &EventId,
+    reply_id: OwnedEventId,
+    config: &Config,
+) {
+    // Save while still holding the lock, so concurrent replies can't save out of order
+    let mut embed_replies = embed_replies.lock().unwrap();
+    embed_replies.insert(source_id, reply_id);
+    if let Err(e) = store::save(
+        config.store_path.as_deref(),
+        EMBED_REPLIES_FILE,
+        &*embed_replies,
+    ) {
+        warn!("Failed to save embed replies: {}", e);
+    }
+}
+
+/// Redacts frogbot's embeds when the message they were for gets redacted
+///
+/// Whoever deleted the message probably wants the link gone too, so the embed shouldn't
+/// outlive it.
+pub async fn redaction_handler(
+    event: OriginalSyncRoomRedactionEvent,
+    room: Room,
+    Ctx(state): Ctx<BotState>,
+) {
+    let Room::Joined(room) = room else {
+        return;
+    };
+    let config = state.config.get();
+
+    let reply_ids = {
+        let mut embed_replies = state.embed_replies.lock().unwrap();
+        let reply_ids = embed_replies.remove(&event.redacts);
+        if reply_ids.is_empty() {
+            return;
+        }
+        if let Err(e) = store::save(
+            config.store_path.as_deref(),
+            EMBED_REPLIES_FILE,
+            &*embed_replies,
+        ) {
+            warn!("Failed to save embed replies: {}", e);
+        }
+        reply_ids
+    };
+
+    for reply_id in reply_ids {
+        info!(
+            "Redacting embed: '{}' for redacted message: '{}'",
+            reply_id, event.redacts
+        );
+        if let Err(e) = room
+            .redact(&reply_id, Some("Original message was deleted"), None)
+            .await
+        {
+            warn!("Failed to redact embed: '{}': {}", reply_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{
+        bot_state, local_client, logged_levels, message_event, record_logs, serve, FakeFetcher,
+        FakeHomeserver,
+    };
+    use hyper::{Body, Request, Response};
+
+    /// Gets the plain and formatted bodies out of a reply frogbot built.
+    fn text_bodies(content: &RoomMessageEventContent) -> (String, String) {
+        let MessageType::Text(text) = &content.msgtype else {
+            panic!("reply isn't a text message");
+        };
+        let formatted = text
+            .formatted
+            .as_ref()
+            .map(|formatted| formatted.body.clone());
+        (text.body.clone(), formatted.unwrap_or_default())
+    }
+
+    /// A page with a title and description, for tests that just need something to embed.
+    const FROG_PAGE: &str = r#"<html><head>
+        <meta property="og:title" content="Frogs">
+        <meta property="og:description" content="All about frogs">
+        </head></html>"#;
+
+    #[test]
+    fn parse_metadata_falls_back_to_twitter_cards() {
+        let page = r#"<html><head>
+            <meta name="twitter:title" content="Tree frogs">
+            <meta name="twitter:description" content="They live in trees, mostly">
+            </head><body></body></html>"#;
+
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Tree frogs");
+        assert_eq!(embed.description, "They live in trees, mostly");
+    }
+
+    #[tokio::test]
+    async fn read_body_refuses_bodies_over_the_limit() {
+        let url = serve(|_| async { Response::new(Body::from(vec![b'a'; 4096])) }).await;
+        let res = local_client().get(&url).send().await.unwrap();
+
+        assert!(read_body(res, 1024).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_body_cuts_off_streamed_bodies_at_the_limit() {
+        // Without a Content-Length the body has to be read before it's known to be too big
+        let url = serve(|_| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                for _ in 0..4 {
+                    if sender.send_data(vec![b'a'; 1024].into()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Response::new(body)
+        })
+        .await;
+        let res = local_client().get(&url).send().await.unwrap();
+
+        let (body, truncated) = read_body(res, 2048).await.unwrap();
+        assert_eq!(body.len(), 2048);
+        assert!(truncated);
+    }
+
+    #[tokio::test]
+    async fn slow_servers_time_out() {
+        let url = serve(|_| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Response::new(Body::from("too late"))
+        })
+        .await;
+        let mut config = Config::default();
+        config.embed_timeout_secs = 1;
+        let client = build_http_client(&config).unwrap();
+
+        let started = Instant::now();
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_timeout());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn is_internal_ip_catches_internal_ranges() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.10",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "::ffff:192.168.1.10",
+            "64:ff9b::a00:1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(
+                is_internal_ip(ip.parse().unwrap()),
+                "{} should be internal",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn is_internal_ip_allows_public_addresses() {
+        for ip in [
+            "93.184.216.34",
+            "1.1.1.1",
+            "2606:4700:4700::1111",
+            "64:ff9b::5db8:d822",
+        ] {
+            assert!(
+                !is_internal_ip(ip.parse().unwrap()),
+                "{} should be public",
+                ip
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ensure_public_host_refuses_ipv6_loopback() {
+        let url = Url::parse("http://[::1]:8080/").unwrap();
+        assert!(ensure_public_host(&url).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ensure_public_host_resolves_hostnames() {
+        // localhost comes from the hosts file, so this doesn't need the network
+        let url = Url::parse("http://localhost:8080/").unwrap();
+        assert!(ensure_public_host(&url).await.is_err());
+    }
+
+    #[test]
+    fn parse_metadata_decodes_double_escaped_entities() {
+        let page = r#"<html><head>
+            <meta property="og:title" content="Frogs &amp;amp; Toads &amp;#8211; a &amp;quot;guide&amp;quot;">
+            <meta property="og:description" content="It&amp;#39;s all about &amp;lt;amphibians&amp;gt;">
+            </head></html>"#;
+
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frogs & Toads – a \"guide\"");
+        assert_eq!(embed.description, "It's all about <amphibians>");
+    }
+
+    #[test]
+    fn truncate_text_leaves_short_text_alone() {
+        assert_eq!(truncate_text("Frogs", 5), "Frogs");
+    }
+
+    #[test]
+    fn truncate_text_breaks_on_words() {
+        assert_eq!(
+            truncate_text("The quick brown frog jumps", 12),
+            "The quick…"
+        );
+    }
+
+    #[test]
+    fn truncate_text_never_splits_characters() {
+        assert_eq!(truncate_text("🐸🐸🐸🐸🐸", 3), "🐸🐸…");
+    }
+
+    #[tokio::test]
+    async fn links_are_fetched_concurrently() {
+        let urls = vec![
+            "https://slow-one.example.com/".to_string(),
+            "https://slow-two.example.com/".to_string(),
+        ];
+        let fetcher = FakeFetcher {
+            pages: urls
+                .iter()
+                .map(|url| (url.clone(), FROG_PAGE.to_string()))
+                .collect(),
+            delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let config = Config::default();
+
+        let fetched: Vec<_> = fetch_links(&urls, &fetcher, &config).collect().await;
+        assert_eq!(fetched.len(), 2);
+        // The second page started loading before the first one was done
+        assert_eq!(fetcher.in_flight.lock().unwrap().1, 2);
+    }
+
+    #[test]
+    fn reserve_fetch_throttles_the_same_domain() {
+        let cooldown = Duration::from_secs(5);
+        let max_wait = Duration::from_secs(30);
+        assert_eq!(
+            reserve_fetch("throttled.example.com", cooldown, max_wait),
+            Some(Duration::ZERO)
+        );
+        let wait = reserve_fetch("throttled.example.com", cooldown, max_wait).unwrap();
+        assert!(wait > Duration::ZERO);
+        assert_eq!(
+            reserve_fetch("unthrottled.example.com", cooldown, max_wait),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn reserve_fetch_gives_up_on_long_waits() {
+        let cooldown = Duration::from_secs(60);
+        assert_eq!(
+            reserve_fetch("busy.example.com", cooldown, Duration::from_secs(1)),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            reserve_fetch("busy.example.com", cooldown, Duration::from_secs(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn host_matches_domain_covers_subdomains() {
+        assert!(host_matches_domain("example.com", "example.com"));
+        assert!(host_matches_domain("www.example.com", "example.com"));
+        assert!(host_matches_domain("www.example.com", ".Example.com"));
+        assert!(!host_matches_domain("notexample.com", "example.com"));
+        assert!(!host_matches_domain("example.com", "www.example.com"));
+    }
+
+    #[test]
+    fn blocklisted_domains_are_skipped() {
+        let mut config = Config::default();
+        config.embed_domain_blocklist = vec!["example.com".to_string()];
+        assert!(!is_domain_allowed("example.com", &config));
+        assert!(!is_domain_allowed("news.example.com", &config));
+        assert!(is_domain_allowed("example.org", &config));
+    }
+
+    #[test]
+    fn only_allowlisted_domains_are_embedded() {
+        let mut config = Config::default();
+        assert!(is_domain_allowed("example.org", &config));
+        config.embed_domain_allowlist = Some(vec!["example.com".to_string()]);
+        assert!(is_domain_allowed("example.com", &config));
+        assert!(is_domain_allowed("news.example.com", &config));
+        assert!(!is_domain_allowed("example.org", &config));
+    }
+
+    #[test]
+    fn blocklist_wins_over_allowlist() {
+        let mut config = Config::default();
+        config.embed_domain_allowlist = Some(vec!["example.com".to_string()]);
+        config.embed_domain_blocklist = vec!["ads.example.com".to_string()];
+        assert!(is_domain_allowed("example.com", &config));
+        assert!(!is_domain_allowed("ads.example.com", &config));
+    }
+
+    #[tokio::test]
+    async fn cached_embeds_are_not_fetched_again() {
+        let url = "https://cached.example.com/frogs";
+        let fetcher = FakeFetcher {
+            pages: HashMap::from([(url.to_string(), FROG_PAGE.to_string())]),
+            ..Default::default()
+        };
+        let config = Config::default();
+
+        let first = get_embed(&fetcher, url, &config).await.unwrap().unwrap();
+        let second = get_embed(&fetcher, url, &config).await.unwrap().unwrap();
+        assert_eq!(first.title, second.title);
+        assert_eq!(*fetcher.fetched.lock().unwrap(), vec![url.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn links_to_the_same_canonical_page_share_an_embed() {
+        let page = r#"<html><head>
+            <title>Frogs</title>
+            <link rel="canonical" href="/frogs">
+            </head></html>"#;
+        let urls = [
+            "https://canonical.example.com/frogs?ref=home",
+            "https://canonical.example.com/frogs?ref=feed",
+        ];
+        let fetcher = FakeFetcher {
+            pages: urls
+                .iter()
+                .map(|url| (url.to_string(), page.to_string()))
+                .collect(),
+            ..Default::default()
+        };
+        let config = Config::default();
+
+        let first = get_embed(&fetcher, urls[0], &config)
+            .await
+            .unwrap()
+            .unwrap();
+        let second = get_embed(&fetcher, urls[1], &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            first.canonical_url.as_deref(),
+            Some("https://canonical.example.com/frogs")
+        );
+        assert_eq!(first.canonical_url, second.canonical_url);
+
+        // The clean URL comes straight from the cache
+        get_embed(&fetcher, "https://canonical.example.com/frogs", &config)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(fetcher.fetched.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn canonical_urls_on_other_hosts_are_ignored() {
+        assert_eq!(
+            same_host_canonical_url("https://example.com/a?b=c", "https://example.com/a"),
+            Some("https://example.com/a".to_string())
+        );
+        assert_eq!(
+            same_host_canonical_url("https://example.com/a", "https://evil.example.net/a"),
+            None
+        );
+    }
+
+    #[test]
+    fn windows_1252_pages_are_decoded() {
+        let page: &[u8] =
+            b"<html><head><meta charset=\"windows-1252\"><title>Caf\xe9 na\xefve</title></head></html>";
+        let encoding = detect_encoding(Some("text/html"), page);
+        assert_eq!(encoding, encoding_rs::WINDOWS_1252);
+        let (html, _, _) = encoding.decode(page);
+        assert_eq!(parse_metadata(&html).unwrap().title, "Café naïve");
+    }
+
+    #[test]
+    fn header_charset_wins_over_meta_charset() {
+        let page = b"<meta charset=\"utf-8\">";
+        assert_eq!(
+            detect_encoding(Some("text/html; charset=\"ISO-8859-1\""), page),
+            encoding_rs::WINDOWS_1252
+        );
+        assert_eq!(detect_encoding(Some("text/html"), page), UTF_8);
+        assert_eq!(detect_encoding(None, b"<p>no charset</p>"), UTF_8);
+    }
+
+    #[test]
+    fn tracking_params_are_stripped() {
+        let tracking_params = vec!["utm_*".to_string(), "fbclid".to_string()];
+        assert_eq!(
+            strip_tracking_params("https://x.com/a?id=1&utm_source=y", &tracking_params),
+            "https://x.com/a?id=1"
+        );
+        assert_eq!(
+            strip_tracking_params("https://x.com/a?fbclid=abc&utm_medium=z", &tracking_params),
+            "https://x.com/a"
+        );
+    }
+
+    #[test]
+    fn urls_without_tracking_params_are_left_alone() {
+        let tracking_params = vec!["utm_*".to_string()];
+        assert_eq!(
+            strip_tracking_params("https://x.com/a?b=%7E&c", &tracking_params),
+            "https://x.com/a?b=%7E&c"
+        );
+    }
+
+    #[test]
+    fn replies_only_embed_new_urls() {
+        let message = "> <@frog:example.com> look at https://example.com/old\n\
+            > and https://example.com/also-old\n\
+            \n\
+            nice, also https://example.com/old and https://example.com/new";
+        assert_eq!(
+            get_new_urls_from_message(message, true),
+            vec!["https://example.com/new"]
+        );
+        assert_eq!(get_new_urls_from_message(message, false).len(), 4);
+    }
+
+    #[test]
+    fn links_over_the_limit_are_left_out() {
+        let urls: Vec<String> = (0..8)
+            .map(|i| format!("https://example.com/{}", i))
+            .collect();
+        let (kept, skipped) = limit_urls(urls.clone(), 3);
+        assert_eq!(kept, urls[..3]);
+        assert_eq!(skipped, 5);
+        assert_eq!(skipped_urls_note(skipped), "(+5 more links not embedded)");
+
+        let (kept, skipped) = limit_urls(urls[..2].to_vec(), 3);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(skipped, 0);
+    }
+
+    #[tokio::test]
+    async fn youtube_links_use_oembed() {
+        let url = "https://www.youtube.com/watch?v=oembed-frogs";
+        let fetcher = FakeFetcher {
+            api_responses: HashMap::from([(
+                "https://www.youtube.com/oembed".to_string(),
+                r#"{"title": "Frog noises", "author_name": "Frog Channel",
+                    "thumbnail_url": "https://i.ytimg.com/vi/frogs/hqdefault.jpg"}"#
+                    .to_string(),
+            )]),
+            ..Default::default()
+        };
+
+        let embed = get_embed(&fetcher, url, &Config::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(embed.title, "Frog noises");
+        assert_eq!(embed.description, "by Frog Channel");
+        assert_eq!(
+            embed.image.as_deref(),
+            Some("https://i.ytimg.com/vi/frogs/hqdefault.jpg")
+        );
+        assert!(!fetcher.fetched.lock().unwrap().contains(&url.to_string()));
+    }
+
+    #[tokio::test]
+    async fn youtube_links_fall_back_to_the_page() {
+        let url = "https://youtu.be/oembed-fallback";
+        let fetcher = FakeFetcher {
+            pages: HashMap::from([(url.to_string(), FROG_PAGE.to_string())]),
+            api_responses: HashMap::from([(
+                "https://www.youtube.com/oembed".to_string(),
+                r#"{"error": "age restricted"}"#.to_string(),
+            )]),
+            ..Default::default()
+        };
+
+        let embed = get_embed(&fetcher, url, &Config::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(embed.title, "Frogs");
+    }
+
+    #[tokio::test]
+    async fn the_configured_user_agent_is_sent() {
+        let url = serve(|request: Request<Body>| async move {
+            let user_agent = request
+                .headers()
+                .get(reqwest::header::USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            Response::new(Body::from(user_agent))
+        })
+        .await;
+
+        let mut config = Config::default();
+        let client = client_builder(&config).unwrap().no_proxy().build().unwrap();
+        let user_agent = client.get(&url).send().await.unwrap().text().await.unwrap();
+        assert_eq!(user_agent, DEFAULT_USER_AGENT);
+
+        config.embed_user_agent = Some("frogbot/1.0 (+https://example.com/frogbot)".to_string());
+        let client = client_builder(&config).unwrap().no_proxy().build().unwrap();
+        let user_agent = client.get(&url).send().await.unwrap().text().await.unwrap();
+        assert_eq!(user_agent, "frogbot/1.0 (+https://example.com/frogbot)");
+    }
+
+    #[tokio::test]
+    async fn the_shared_api_client_keeps_the_config() {
+        let url = serve(|request: Request<Body>| async move {
+            let header = |name| {
+                request
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let headers = [
+                header(reqwest::header::USER_AGENT),
+                header(reqwest::header::ACCEPT),
+                header(reqwest::header::AUTHORIZATION),
+            ];
+            Response::new(Body::from(headers.join("\n")))
+        })
+        .await;
+        let mut config = Config::default();
+        config.embed_user_agent = Some("frogbot-test".to_string());
+        let fetcher = HttpFetcher::new(
+            local_client(),
+            client_builder(&config).unwrap().no_proxy().build().unwrap(),
+            Arc::new(config),
+        );
+
+        for _ in 0..2 {
+            let response = fetcher
+                .fetch_api(ApiRequest {
+                    url: Url::parse(&url).unwrap(),
+                    accept: "application/json",
+                    bearer_token: Some("secret".to_string()),
+                })
+                .await
+                .unwrap();
+            assert_eq!(response, "frogbot-test\napplication/json\nBearer secret");
+        }
+    }
+
+    #[test]
+    fn notices_are_embedded() {
+        use matrix_sdk::ruma::events::room::message::NoticeMessageEventContent;
+
+        let notice = MessageType::Notice(NoticeMessageEventContent::html(
+            "New release: https://example.com/notice-release",
+            "New release: <a href=\"https://example.com/notice-release\">v1.0</a>",
+        ));
+        let (body, formatted) = message_text(notice).unwrap();
+        let urls = message_urls(&body, formatted, false, &Config::default());
+        assert_eq!(urls.len(), 2);
+
+        // The link is in both bodies, but only gets embedded once
+        let event_id = <&EventId>::try_from("$notice:example.com").unwrap();
+        assert_eq!(
+            new_message_urls(event_id, urls),
+            vec!["https://example.com/notice-release"]
+        );
+    }
+
+    #[test]
+    fn formatted_links_are_embedded() {
+        let formatted = FormattedBody::html(
+            "<mx-reply><a href=\"https://example.com/quoted\">quoted</a></mx-reply>\
+             see <a href=\"https://example.com/docs\">the docs</a>, \
+             <a href=\"https://matrix.to/#/@frog:example.com\">Frog</a> and \
+             <a href=\"mailto:frog@example.com\">email</a>",
+        );
+        let urls = message_urls("see the docs", Some(formatted), true, &Config::default());
+        assert_eq!(urls, vec!["https://example.com/docs"]);
+    }
+
+    #[test]
+    fn other_message_types_are_ignored() {
+        use matrix_sdk::ruma::events::room::message::EmoteMessageEventContent;
+
+        let emote = EmoteMessageEventContent::plain("shares https://example.com");
+        assert!(message_text(MessageType::Emote(emote)).is_none());
+    }
+
+    #[test]
+    fn embeds_can_be_turned_off_per_room() {
+        let config = Config::default();
+        let quiet_room = <&RoomId>::try_from("!quiet:example.com").unwrap();
+        let other_room = <&RoomId>::try_from("!other:example.com").unwrap();
+        let mut settings = EmbedSettings::default();
+        assert!(settings.embeds_enabled(quiet_room, &config));
+
+        settings.rooms.insert(quiet_room.to_owned(), false);
+        assert!(!settings.embeds_enabled(quiet_room, &config));
+        assert!(settings.embeds_enabled(other_room, &config));
+    }
+
+    #[test]
+    fn rooms_can_turn_embeds_on_when_they_are_off_by_default() {
+        let mut config = Config::default();
+        config.embeds_enabled_by_default = false;
+        let room = <&RoomId>::try_from("!loud:example.com").unwrap();
+        let mut settings = EmbedSettings::default();
+        assert!(!settings.embeds_enabled(room, &config));
+
+        settings.rooms.insert(room.to_owned(), true);
+        assert!(settings.embeds_enabled(room, &config));
+    }
+
+    #[test]
+    fn edits_only_embed_new_urls() {
+        let event_id = <&EventId>::try_from("$edited:example.com").unwrap();
+        let original = vec!["https://example.com/original".to_string()];
+        assert_eq!(new_message_urls(event_id, original.clone()), original);
+
+        let edited = vec![
+            "https://example.com/original".to_string(),
+            "https://example.com/added".to_string(),
+        ];
+        assert_eq!(
+            new_message_urls(event_id, edited),
+            vec!["https://example.com/added"]
+        );
+    }
+
+    #[test]
+    fn redacted_messages_give_back_their_replies() {
+        let source = <&EventId>::try_from("$source:example.com").unwrap();
+        let mut replies = EmbedReplies::default();
+        replies.insert(source, "$embed-one:example.com".try_into().unwrap());
+        replies.insert(source, "$embed-two:example.com".try_into().unwrap());
+
+        let removed = replies.remove(source);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(removed[1].as_str(), "$embed-two:example.com");
+        assert!(replies.remove(source).is_empty());
+    }
+
+    #[test]
+    fn embed_replies_are_saved_to_the_store() {
+        let store_path =
+            std::env::temp_dir().join(format!("frogbot-embed-replies-{}", std::process::id()));
+        let mut config = Config::default();
+        config.store_path = Some(store_path.clone());
+        let source = <&EventId>::try_from("$saved:example.com").unwrap();
+
+        let embed_replies = Mutex::new(EmbedReplies::default());
+        record_reply(
+            &embed_replies,
+            source,
+            "$saved-embed:example.com".try_into().unwrap(),
+            &config,
+        );
+
+        let mut saved: EmbedReplies = store::load(Some(&store_path), EMBED_REPLIES_FILE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(saved.remove(source)[0].as_str(), "$saved-embed:example.com");
+        let _ = std::fs::remove_dir_all(store_path);
+    }
+
+    #[test]
+    fn only_the_newest_replies_are_remembered() {
+        let mut replies = EmbedReplies::default();
+        let event_ids: Vec<OwnedEventId> = (0..=MAX_TRACKED_REPLIES)
+            .map(|i| format!("$message-{}:example.com", i).try_into().unwrap())
+            .collect();
+        for event_id in &event_ids {
+            replies.insert(event_id, "$embed:example.com".try_into().unwrap());
+        }
+        assert!(replies.remove(&event_ids[0]).is_empty());
+        assert_eq!(replies.remove(&event_ids[MAX_TRACKED_REPLIES]).len(), 1);
+    }
+
+    #[test]
+    fn embed_html_is_escaped() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/bold",
+        }));
+        let embed = Embed::new(
+            "<b>Bold</b> frogs".to_string(),
+            "<script>alert(1)</script>".to_string(),
+        );
+
+        let content = embed.to_message_content(&Config::default(), &reply_to);
+        let (body, formatted) = text_bodies(&content);
+        assert!(body.ends_with("<b>Bold</b> frogs"));
+        assert!(formatted.contains("&lt;b&gt;Bold&lt;/b&gt; frogs"));
+        assert!(formatted.contains("&lt;script&gt;"));
+        assert!(!formatted.contains("<b>"));
+        assert!(!formatted.contains("<script>"));
+    }
+
+    #[test]
+    fn embeds_reply_with_the_title_and_description() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/frogs",
+        }));
+        let embed = Embed::new("Frogs".to_string(), "All about frogs".to_string());
+
+        let content = embed.to_message_content(&Config::default(), &reply_to);
+        let (body, formatted) = text_bodies(&content);
+        assert!(body.ends_with("Frogs"));
+        assert!(formatted.contains("<h4>Frogs</h4>"));
+        assert!(formatted.contains("<p>All about frogs</p>"));
+        assert!(matches!(
+            &content.relates_to,
+            Some(Relation::Reply { in_reply_to }) if in_reply_to.event_id == reply_to.event_id
+        ));
+    }
+
+    #[test]
+    fn embeds_only_show_uploaded_images() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/frogs",
+        }));
+        let mut embed = Embed::new("Frogs".to_string(), "All about frogs".to_string());
+
+        embed.image = Some("https://example.com/frog.png".to_string());
+        let (_, formatted) = text_bodies(&embed.to_message_content(&Config::default(), &reply_to));
+        assert!(!formatted.contains("<img"));
+
+        embed.image = Some("mxc://example.com/frog".to_string());
+        let (_, formatted) = text_bodies(&embed.to_message_content(&Config::default(), &reply_to));
+        assert!(formatted.contains("<img src=\"mxc://example.com/frog\">"));
+    }
+
+    #[tokio::test]
+    async fn embeds_come_from_the_fetcher() {
+        let url = "https://fetched.example.com/frogs";
+        let page = r#"<html><head>
+            <meta property="og:title" content="Tree frogs">
+            <meta property="og:description" content="They live in trees">
+            <meta property="og:image" content="https://fetched.example.com/tree-frog.png">
+            <meta property="og:site_name" content="Frog Facts">
+            </head></html>"#;
+        let fetcher = FakeFetcher {
+            pages: HashMap::from([(url.to_string(), page.to_string())]),
+            ..Default::default()
+        };
+
+        let embed = get_embed(&fetcher, url, &Config::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(embed.title, "Tree frogs");
+        assert_eq!(embed.description, "They live in trees");
+        assert_eq!(
+            embed.image.as_deref(),
+            Some("https://fetched.example.com/tree-frog.png")
+        );
+        assert_eq!(embed.site_name.as_deref(), Some("Frog Facts"));
+    }
+
+    #[tokio::test]
+    async fn fetch_errors_are_passed_on() {
+        let fetcher = FakeFetcher::default();
+        let embed = get_embed(&fetcher, "https://missing.example.com/", &Config::default()).await;
+        assert!(embed.is_err());
+    }
+
+    #[test]
+    fn meta_tags_without_content_are_skipped() {
+        let page = r#"<html><head>
+            <title>Frogs</title>
+            <meta name="description">
+            <meta property="og:title" content="  ">
+            </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frogs");
+        assert_eq!(embed.description, "");
+    }
+
+    #[test]
+    fn pages_without_metadata_are_told_apart_from_malformed_ones() {
+        assert_eq!(
+            parse_metadata("<html><head></head><body></body></html>").unwrap_err(),
+            EmbedParseError::NoMetadata
+        );
+        assert_eq!(
+            parse_metadata("just some text").unwrap_err(),
+            EmbedParseError::Malformed
+        );
+        assert_eq!(parse_metadata("").unwrap_err(), EmbedParseError::Malformed);
+    }
+
+    #[test]
+    fn bare_pages_fall_back_to_headings_and_paragraphs() {
+        let page = "<html><body>
+            <h1>  Tree
+                frogs </h1>
+            <p>By Frog Facts</p>
+            <p>Tree frogs spend most of their lives   up in the branches of trees.</p>
+            </body></html>";
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Tree frogs");
+        assert_eq!(
+            embed.description,
+            "Tree frogs spend most of their lives up in the branches of trees."
+        );
+    }
+
+    #[test]
+    fn site_names_are_stripped_from_titles() {
+        assert_eq!(strip_site_name("Frogs - Frog Facts", "Frog Facts"), "Frogs");
+        assert_eq!(strip_site_name("Frogs | Frog Facts", "Frog Facts"), "Frogs");
+        assert_eq!(strip_site_name("Frogs — Frog Facts", "Frog Facts"), "Frogs");
+    }
+
+    #[test]
+    fn dashes_in_titles_are_kept() {
+        assert_eq!(
+            strip_site_name("Spider-Man - a frog's review", "Frog Facts"),
+            "Spider-Man - a frog's review"
+        );
+        assert_eq!(
+            strip_site_name("Frogs - the good - Frog Facts", "Frog Facts"),
+            "Frogs - the good"
+        );
+        assert_eq!(
+            strip_site_name(" - Frog Facts", "Frog Facts"),
+            " - Frog Facts"
+        );
+    }
+
+    #[test]
+    fn parse_metadata_strips_the_site_name() {
+        let page = r#"<html><head>
+            <meta property="og:title" content="Tree frogs | Frog Facts">
+            <meta property="og:site_name" content="Frog Facts">
+            </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Tree frogs");
+        assert_eq!(embed.site_name.as_deref(), Some("Frog Facts"));
+    }
+
+    #[test]
+    fn no_metadata_replies_are_off_by_default() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/nothing",
+        }));
+        let mut config = Config::default();
+        assert!(no_metadata_reply(&config, &reply_to).is_none());
+
+        config.reply_on_no_metadata = true;
+        let content = no_metadata_reply(&config, &reply_to).unwrap();
+        let (body, _) = text_bodies(&content);
+        assert!(body.ends_with("Couldn't parse metadata for URL"));
+    }
+
+    #[test]
+    fn embeds_stay_in_threads() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/threaded",
+            "m.relates_to": {
+                "rel_type": "m.thread",
+                "event_id": "$root:example.com",
+                "is_falling_back": true,
+                "m.in_reply_to": { "event_id": "$root:example.com" },
+            },
+        }));
+        let embed = Embed::new("Frogs".to_string(), String::new());
+
+        let content = embed.to_message_content(&Config::default(), &reply_to);
+        let relation = &serde_json::to_value(&content).unwrap()["m.relates_to"];
+        assert_eq!(relation["rel_type"], "m.thread");
+        assert_eq!(relation["event_id"], "$root:example.com");
+        assert_eq!(
+            relation["m.in_reply_to"]["event_id"],
+            "$message:example.com"
+        );
+    }
+
+    #[test]
+    fn embeds_outside_threads_are_plain_replies() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/unthreaded",
+        }));
+        let content = Embed::new("Frogs".to_string(), String::new())
+            .to_message_content(&Config::default(), &reply_to);
+        assert!(matches!(content.relates_to, Some(Relation::Reply { .. })));
+    }
+
+    #[test]
+    fn opted_out_users_dont_get_embeds() {
+        let opted_out = <&UserId>::try_from("@private:example.com").unwrap();
+        let someone = <&UserId>::try_from("@someone:example.com").unwrap();
+        let mut settings = EmbedSettings::default();
+        settings.opted_out_users.insert(opted_out.to_owned());
+        assert!(settings.is_opted_out(opted_out));
+        assert!(!settings.is_opted_out(someone));
+    }
+
+    #[test]
+    fn embed_settings_saved_before_opt_outs_still_load() {
+        let settings: EmbedSettings =
+            serde_json::from_str(r#"{"rooms": {"!room:example.com": false}}"#).unwrap();
+        assert!(settings.opted_out_users.is_empty());
+        assert_eq!(settings.rooms.len(), 1);
+    }
+
+    #[test]
+    fn messages_with_the_opt_out_marker_get_no_embeds() {
+        let mut config = Config::default();
+        config.embed_opt_out_marker = "#noembed".to_string();
+
+        let urls = message_urls("https://example.com/frogs #noembed", None, false, &config);
+        assert!(urls.is_empty());
+        let urls = message_urls("https://example.com/frogs", None, false, &config);
+        assert_eq!(urls, vec!["https://example.com/frogs"]);
+        // Only the marker as a word of its own counts
+        assert!(has_opt_out_marker(
+            "#noembed https://example.com",
+            "#noembed"
+        ));
+        assert!(!has_opt_out_marker(
+            "https://example.com/#noembed",
+            "#noembed"
+        ));
+        assert!(!has_opt_out_marker("https://example.com", ""));
+    }
+
+    #[test]
+    fn urls_lose_the_punctuation_after_them() {
+        assert_eq!(
+            get_urls_from_message(
+                "Look at https://example.com/frogs. Or https://example.org/toads!"
+            ),
+            vec!["https://example.com/frogs", "https://example.org/toads"]
+        );
+        assert_eq!(
+            get_urls_from_message("Frogs? https://example.com/frogs?, toads: https://example.com;"),
+            vec!["https://example.com/frogs", "https://example.com"]
+        );
+    }
+
+    #[test]
+    fn urls_lose_the_brackets_around_them() {
+        assert_eq!(
+            get_urls_from_message("(see https://example.com/frogs)"),
+            vec!["https://example.com/frogs"]
+        );
+        assert_eq!(
+            get_urls_from_message("[https://example.com/frogs]."),
+            vec!["https://example.com/frogs"]
+        );
+        assert_eq!(
+            get_urls_from_message("(https://en.wikipedia.org/wiki/Frog_(disambiguation))"),
+            vec!["https://en.wikipedia.org/wiki/Frog_(disambiguation)"]
+        );
+    }
+
+    #[test]
+    fn urls_in_markdown_links_are_found() {
+        assert_eq!(
+            get_urls_from_message("Read [this](https://example.com/frogs) now"),
+            vec!["https://example.com/frogs"]
+        );
+    }
+
+    #[test]
+    fn local_urls_are_ignored() {
+        assert!(get_urls_from_message("http://localhost:8080/admin").is_empty());
+        assert!(get_urls_from_message("http://127.0.0.1/admin").is_empty());
+    }
+
+    #[test]
+    fn markdown_links_have_no_stray_paren() {
+        assert_eq!(
+            get_markdown_links("[click here](https://example.com)"),
+            vec![("click here", "https://example.com")]
+        );
+        assert_eq!(
+            get_markdown_links(
+                "([frogs](https://example.com/frogs)), and [ ](https://example.org)"
+            ),
+            vec![("frogs", "https://example.com/frogs")]
+        );
+        assert_eq!(
+            get_markdown_links("[Frog](https://en.wikipedia.org/wiki/Frog_(disambiguation))"),
+            vec![(
+                "Frog",
+                "https://en.wikipedia.org/wiki/Frog_(disambiguation)"
+            )]
+        );
+        assert_eq!(
+            get_urls_from_message("[click here](https://example.com)"),
+            vec!["https://example.com"]
+        );
+    }
+
+    #[test]
+    fn urls_are_cut_at_unmatched_parens() {
+        assert_eq!(
+            cut_at_unmatched_paren("https://example.com/a)b"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            cut_at_unmatched_paren("https://example.com/(a)b)"),
+            "https://example.com/(a)b"
+        );
+        assert_eq!(
+            cut_at_unmatched_paren("https://example.com/a"),
+            "https://example.com/a"
+        );
+    }
+
+    #[test]
+    fn github_issue_links_are_recognised() {
+        let issue =
+            GitHubIssue::from_url(&Url::parse("https://github.com/frogs/pond/issues/12").unwrap())
+                .unwrap();
+        assert_eq!(
+            (issue.owner.as_str(), issue.repo.as_str()),
+            ("frogs", "pond")
+        );
+        assert_eq!(issue.number, 12);
+        assert!(GitHubIssue::from_url(
+            &Url::parse("https://github.com/frogs/pond/pull/7").unwrap()
+        )
+        .is_some());
+
+        for url in [
+            "https://github.com/frogs/pond",
+            "https://github.com/frogs/pond/wiki/12",
+            "https://github.com/frogs/pond/issues/new",
+            "https://gitlab.com/frogs/pond/issues/12",
+        ] {
+            assert!(GitHubIssue::from_url(&Url::parse(url).unwrap()).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn github_links_are_embedded_from_the_api() {
+        let mut fetcher = FakeFetcher::default();
+        fetcher.api_responses.insert(
+            "https://api.github.com/repos/frogs/pond/issues/7".to_string(),
+            serde_json::json!({
+                "title": "Add lily pads",
+                "state": "closed",
+                "user": { "login": "kermit" },
+                "labels": [{ "name": "enhancement" }, { "name": "pond" }],
+                "pull_request": { "merged_at": "2023-01-01T00:00:00Z" },
+            })
+            .to_string(),
+        );
+
+        let embed = get_embed(
+            &fetcher,
+            "https://github.com/frogs/pond/pull/7",
+            &Config::default(),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(embed.title, "Add lily pads");
+        assert_eq!(
+            embed.description,
+            "Pull request #7 in frogs/pond · merged · by kermit · 2 labels"
+        );
+        assert!(fetcher
+            .fetched
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|url| url.starts_with("https://api")));
+    }
+
+    #[tokio::test]
+    async fn github_links_fall_back_to_the_page() {
+        let url = "https://github.com/frogs/pond/issues/404";
+        let mut fetcher = FakeFetcher::default();
+        fetcher.pages.insert(url.to_string(), FROG_PAGE.to_string());
+
+        let embed = get_embed(&fetcher, url, &Config::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(embed.title, "Frogs");
+    }
+
+    #[tokio::test]
+    async fn fetches_are_capped_across_messages() {
+        let config = Config::default();
+        let max_fetches = config.embed_max_concurrent_fetches;
+        let urls: Vec<String> = (0..max_fetches * 2)
+            .map(|i| format!("https://example.com/capped-{}", i))
+            .collect();
+        let mut fetcher = FakeFetcher {
+            delay: Duration::from_millis(100),
+            ..FakeFetcher::default()
+        };
+        for url in &urls {
+            fetcher.pages.insert(url.clone(), FROG_PAGE.to_string());
+        }
+        let fetcher = Arc::new(fetcher);
+
+        let tasks: Vec<_> = urls
+            .into_iter()
+            .map(|url| {
+                let fetcher = fetcher.clone();
+                let config = config.clone();
+                tokio::spawn(async move { get_embed(&*fetcher, &url, &config).await.is_ok() })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap());
+        }
+
+        let (in_flight, most_in_flight) = *fetcher.in_flight.lock().unwrap();
+        assert_eq!(in_flight, 0);
+        assert!(most_in_flight <= max_fetches);
+        assert_eq!(fetcher.fetched.lock().unwrap().len(), max_fetches * 2);
+    }
+
+    #[test]
+    fn selectors_all_compile() {
+        for selector in [
+            &*OG_TITLE_SELECTOR,
+            &*OG_DESCRIPTION_SELECTOR,
+            &*TITLE_SELECTOR,
+            &*DESCRIPTION_SELECTOR,
+            &*TWITTER_TITLE_SELECTOR,
+            &*TWITTER_DESCRIPTION_SELECTOR,
+            &*OG_IMAGE_SELECTOR,
+            &*CANONICAL_SELECTOR,
+            &*H1_SELECTOR,
+            &*PARAGRAPH_SELECTOR,
+            &*OG_SITE_NAME_SELECTOR,
+            &*HTML_LANG_SELECTOR,
+            &*OG_LOCALE_SELECTOR,
+            &*JSON_LD_SELECTOR,
+            &*LINK_SELECTOR,
+        ] {
+            // Selecting from an empty page is enough to know it parsed
+            assert_eq!(Html::parse_document("").select(selector).count(), 0);
+        }
+    }
+
+    #[test]
+    fn parse_metadata_prefers_open_graph() {
+        let page = r#"<html><head>
+            <title>Frogs | Example</title>
+            <meta name="description" content="A page about frogs">
+            <meta property="og:title" content="Frogs">
+            <meta property="og:description" content="All about frogs">
+            </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frogs");
+        assert_eq!(embed.description, "All about frogs");
+
+        let page = r#"<html><head>
+            <title>Frogs | Example</title>
+            <meta name="description" content="A page about frogs">
+            </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frogs | Example");
+        assert_eq!(embed.description, "A page about frogs");
+    }
+
+    /// Held by the tests that run [`embed_handler`], since one of them takes every background
+    /// task slot.
+    static EMBED_TASKS_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Builds a message event as it comes from a sync, with `event_id` and a link to `url`.
+    fn sync_message_event(event_id: &str, url: &str) -> OriginalSyncRoomMessageEvent {
+        serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": "@frog:example.com",
+            "origin_server_ts": 1_700_000_000_000u64,
+            "content": { "msgtype": "m.text", "body": format!("Look at {}", url) },
+        }))
+        .unwrap()
+    }
+
+    /// Builds an edit of `event_id` as it comes from a sync, with a link to `url`.
+    fn sync_edit_event(event_id: &str, url: &str) -> OriginalSyncRoomMessageEvent {
+        serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": format!("{}-edit", event_id),
+            "sender": "@frog:example.com",
+            "origin_server_ts": 1_700_000_000_000u64,
+            "content": {
+                "msgtype": "m.text",
+                "body": format!("* Look at {}", url),
+                "m.new_content": { "msgtype": "m.text", "body": format!("Look at {}", url) },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": event_id },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn slow_embeds_dont_hold_up_other_messages() {
+        let _lock = EMBED_TASKS_LOCK.lock().await;
+        let urls = [
+            "https://example.com/slow-task-1",
+            "https://example.com/slow-task-2",
+            "https://example.com/slow-task-busy",
+        ];
+        let mut fetcher = FakeFetcher {
+            delay: Duration::from_secs(2),
+            ..FakeFetcher::default()
+        };
+        for url in urls {
+            fetcher.pages.insert(url.to_string(), FROG_PAGE.to_string());
+        }
+        let state = bot_state(Config::default(), fetcher);
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let client = homeserver.client().await;
+        let room = client
+            .get_room(<&RoomId>::try_from("!room:example.com").unwrap())
+            .unwrap();
+
+        let started = Instant::now();
+        for (i, url) in urls[..2].iter().enumerate() {
+            let event = sync_message_event(&format!("$slow-task-{}:example.com", i), url);
+            embed_handler(event, room.clone(), client.clone(), Ctx(state.clone())).await;
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(homeserver.sent_messages().is_empty());
+
+        // Both pages load at the same time in the background
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(homeserver.sent_messages().len(), 2);
+
+        // Messages that come in while every slot is taken are skipped
+        let busy = EMBED_TASKS
+            .clone()
+            .try_acquire_many_owned(MAX_EMBED_TASKS as u32)
+            .unwrap();
+        let event = sync_message_event("$slow-task-busy:example.com", urls[2]);
+        embed_handler(event, room.clone(), client.clone(), Ctx(state.clone())).await;
+        drop(busy);
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(homeserver.sent_messages().len(), 2);
+
+        // But their links weren't remembered, so editing the message still embeds them
+        let event = sync_edit_event("$slow-task-busy:example.com", urls[2]);
+        embed_handler(event, room, client, Ctx(state)).await;
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(homeserver.sent_messages().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn fetches_go_through_the_configured_proxy() {
+        let proxied = Arc::new(Mutex::new(Vec::new()));
+        let proxy_url = {
+            let proxied = proxied.clone();
+            serve(move |request: Request<Body>| {
+                let authorization = request
+                    .headers()
+                    .get("proxy-authorization")
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+                proxied
+                    .lock()
+                    .unwrap()
+                    .push((request.uri().to_string(), authorization));
+                async { Response::new(Body::from(FROG_PAGE)) }
+            })
+            .await
+        };
+        let mut config = Config::default();
+        config.http_proxy = Some(proxy_url.replace("http://", "http://frog:ribbit@"));
+
+        let client = client_builder(&config).unwrap().build().unwrap();
+        let page = client
+            .get("http://frogs.invalid/pond")
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(page, FROG_PAGE);
+        // "frog:ribbit" in base64
+        assert_eq!(
+            *proxied.lock().unwrap(),
+            [(
+                "http://frogs.invalid/pond".to_string(),
+                Some("Basic ZnJvZzpyaWJiaXQ=".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn configured_proxies_beat_the_environment() {
+        let configured = Some("http://proxy.example.com:8080".to_string());
+        assert_eq!(proxy_setting(&configured, "FROGBOT_TEST_PROXY"), configured);
+        assert_eq!(proxy_setting(&None, "FROGBOT_TEST_UNSET_PROXY"), None);
+        assert_eq!(
+            proxy_setting(&Some(" ".to_string()), "FROGBOT_TEST_UNSET_PROXY"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn fetches_go_through_the_socks_proxy() {
+        use std::io::Read;
+
+        // Just enough of a SOCKS proxy to see that the client starts talking SOCKS5 to it
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = Config::default();
+        config.socks_proxy = Some(format!("socks5h://{}", listener.local_addr().unwrap()));
+        let greeting = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut version = [0; 1];
+            stream.read_exact(&mut version).unwrap();
+            version[0]
+        });
+
+        let client = client_builder(&config).unwrap().build().unwrap();
+        // The fake proxy never answers, so the request itself fails
+        let _ = client
+            .get("http://frogs.invalid/")
+            .timeout(Duration::from_secs(2))
+            .send()
+            .await;
+        assert_eq!(greeting.join().unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn redirects_to_internal_addresses_are_blocked() {
+        // The "site" is reached through a proxy, so it can have a public address without needing
+        // the network
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let proxy_url = {
+            let requested = requested.clone();
+            serve(move |request: Request<Body>| {
+                requested.lock().unwrap().push(request.uri().to_string());
+                let location = match request.uri().path() {
+                    "/start" => "http://93.184.216.34/next",
+                    _ => "http://169.254.169.254/latest/meta-data",
+                };
+                async move {
+                    Response::builder()
+                        .status(302)
+                        .header("location", location)
+                        .body(Body::empty())
+                        .unwrap()
+                }
+            })
+            .await
+        };
+        let mut config = Config::default();
+        config.http_proxy = Some(proxy_url);
+        let client = client_builder(&config)
+            .unwrap()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+
+        let error = send_request(&client, "http://93.184.216.34/start", &config)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "won't redirect to an internal address");
+        assert_eq!(
+            *requested.lock().unwrap(),
+            ["http://93.184.216.34/start", "http://93.184.216.34/next"]
+        );
+    }
+
+    #[test]
+    fn redirect_error_checks_each_hop() {
+        let url = |url| Url::parse(url).unwrap();
+        let https = url("https://example.com/");
+        assert_eq!(
+            redirect_error(&https, &url("https://example.org/"), 0, 3),
+            None
+        );
+        assert!(redirect_error(&https, &url("https://example.org/"), 3, 3).is_some());
+        assert!(redirect_error(&https, &url("http://example.org/"), 0, 3).is_some());
+        assert!(redirect_error(&https, &url("file:///etc/passwd"), 0, 3).is_some());
+        assert!(redirect_error(&https, &url("https://10.0.0.1/"), 0, 3).is_some());
+        assert!(redirect_error(&https, &url("https://[::1]/"), 0, 3).is_some());
+        assert_eq!(
+            redirect_error(
+                &url("http://example.com/"),
+                &url("https://example.org/"),
+                0,
+                3
+            ),
+            None
+        );
+    }
+
+    /// A page with a title and description, gzipped.
+    const GZIPPED_PAGE: &[u8] = &[
+        31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 109, 206, 193, 13, 194, 48, 16, 68, 209, 86, 44, 55, 144,
+        123, 228, 228, 66, 9, 84, 96, 197, 67, 108, 201, 246, 154, 221, 1, 137, 238, 33, 225, 194,
+        129, 2, 158, 254, 15, 153, 173, 174, 33, 35, 166, 53, 52, 48, 186, 161, 50, 160, 124, 45,
+        94, 246, 153, 133, 21, 222, 109, 210, 137, 206, 197, 95, 239, 143, 104, 25, 201, 221, 84,
+        118, 243, 255, 72, 130, 109, 90, 6, 139, 244, 31, 120, 145, 54, 20, 102, 7, 21, 117, 212,
+        248, 68, 253, 248, 233, 91, 158, 206, 141, 55, 29, 96, 3, 24, 141, 0, 0, 0,
+    ];
+
+    #[tokio::test]
+    async fn gzipped_pages_are_decompressed() {
+        let url = serve(|_| async {
+            Response::builder()
+                .header("content-type", "text/html")
+                .header("content-encoding", "gzip")
+                .body(Body::from(GZIPPED_PAGE))
+                .unwrap()
+        })
+        .await;
+        let client = client_builder(&Config::default())
+            .unwrap()
+            .no_proxy()
+            .build()
+            .unwrap();
+
+        let res = client.get(&url).send().await.unwrap();
+        let (body, _) = read_body(res, 4096).await.unwrap();
+        let embed = parse_metadata(&String::from_utf8(body).unwrap()).unwrap();
+        assert_eq!(embed.title, "Squashed frogs");
+        assert_eq!(embed.description, "Compressed for travel");
+    }
+
+    #[test]
+    fn embeds_say_which_site_they_are_from() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.com/frogs",
+        }));
+        let mut embed = Embed::new("Frogs".to_string(), "All about frogs".to_string());
+        embed.site_name = Some("Frog Facts & Figures".to_string());
+
+        let (body, formatted) =
+            text_bodies(&embed.to_message_content(&Config::default(), &reply_to));
+        assert!(body.contains("via Frog Facts & Figures\nFrogs"));
+        assert!(formatted.contains("<sub>via Frog Facts &amp; Figures</sub>"));
+
+        embed.site_name = None;
+        let (body, formatted) =
+            text_bodies(&embed.to_message_content(&Config::default(), &reply_to));
+        assert!(!body.contains("via "));
+        assert!(!formatted.contains("<sub>"));
+    }
+
+    /// Records the spans that get created, as their name and fields.
+    struct SpanRecorder(Arc<Mutex<Vec<String>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanRecorder {
+        fn on_new_span(
+            &self,
+            attributes: &tracing::span::Attributes<'_>,
+            _: &tracing::span::Id,
+            _: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut span = attributes.metadata().name().to_string();
+            attributes.record(
+                &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                    span.push_str(&format!(" {}={:?}", field.name(), value));
+                },
+            );
+            self.0.lock().unwrap().push(span);
+        }
+    }
+
+    #[tokio::test]
+    async fn embeds_are_fetched_in_a_span() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let spans = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanRecorder(spans.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let url = "https://example.com/traced";
+        let fetcher = FakeFetcher {
+            pages: HashMap::from([(url.to_string(), FROG_PAGE.to_string())]),
+            ..Default::default()
+        };
+
+        get_embed(&fetcher, url, &Config::default()).await.unwrap();
+        let spans = spans.lock().unwrap();
+        assert!(spans.contains(&format!("get_embed url={:?}", url)));
+        assert!(spans.iter().any(|span| span.starts_with("parse_metadata")));
+    }
+
+    #[tokio::test]
+    async fn sent_embeds_are_counted() {
+        let url = "https://example.com/counted";
+        let fetcher = FakeFetcher {
+            pages: HashMap::from([(url.to_string(), FROG_PAGE.to_string())]),
+            ..Default::default()
+        };
+        let state = bot_state(Config::default(), fetcher);
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let client = homeserver.client().await;
+        let room = client
+            .get_room(<&RoomId>::try_from("!room:example.com").unwrap())
+            .unwrap();
+        let embeds_sent = metrics::EMBEDS_SENT.get();
+
+        let _lock = EMBED_TASKS_LOCK.lock().await;
+        let event = sync_message_event("$counted:example.com", url);
+        embed_handler(event, room, client, Ctx(state)).await;
+        // Other tests send embeds too, so this can only check it went up
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics::EMBEDS_SENT.get() == embeds_sent {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(homeserver.sent_messages().len(), 1);
+    }
+
+    #[test]
+    fn image_links_are_recognised() {
+        assert!(is_image_url("https://example.com/frog.png"));
+        assert!(is_image_url(
+            "https://example.com/photos/FROG.JPG?size=large"
+        ));
+        assert!(is_image_url("https://example.com/frog.webp"));
+        assert!(!is_image_url("https://example.com/frog.png.html"));
+        assert!(!is_image_url("https://example.com/frogs"));
+        assert!(!is_image_url("not a url.png"));
+    }
+
+    #[tokio::test]
+    async fn image_links_are_uploaded_and_sent() {
+        let url = "https://example.com/uploaded-frog.png";
+        let fetcher = FakeFetcher {
+            images: HashMap::from([(url.to_string(), b"\x89PNG frog".to_vec())]),
+            ..Default::default()
+        };
+        let mut config = Config::default();
+        config.embed_images = true;
+        let state = bot_state(config, fetcher);
+        let homeserver = FakeHomeserver::with_rooms(
+            &["!room:example.com"],
+            vec![(
+                "POST",
+                "/upload",
+                serde_json::json!({ "content_uri": "mxc://example.com/frog" }),
+            )],
+        )
+        .await;
+        let client = homeserver.client().await;
+        let room = client
+            .get_room(<&RoomId>::try_from("!room:example.com").unwrap())
+            .unwrap();
+
+        let _lock = EMBED_TASKS_LOCK.lock().await;
+        let event = sync_message_event("$image:example.com", url);
+        embed_handler(event, room, client, Ctx(state)).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while homeserver.sent_messages().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let uploads = homeserver.requests("POST", "/upload");
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].body, "\u{fffd}PNG frog");
+        let sent = homeserver.sent_messages();
+        assert_eq!(sent[0]["msgtype"], "m.image");
+        assert_eq!(sent[0]["url"], "mxc://example.com/frog");
+        assert!(sent[0]["body"]
+            .as_str()
+            .unwrap()
+            .ends_with("uploaded-frog.png"));
+    }
+
+    #[test]
+    fn page_languages_are_named() {
+        let page = r#"<html lang="fr"><head><title>Les grenouilles</title></head></html>"#;
+        assert_eq!(
+            parse_metadata(page).unwrap().language.as_deref(),
+            Some("French")
+        );
+
+        let page = r#"<html><head>
+            <meta property="og:title" content="Frogs">
+            <meta property="og:locale" content="en_US">
+        </head></html>"#;
+        assert_eq!(
+            parse_metadata(page).unwrap().language.as_deref(),
+            Some("English")
+        );
+
+        let page = "<html><head><title>Frogs</title></head></html>";
+        assert_eq!(parse_metadata(page).unwrap().language, None);
+    }
+
+    #[test]
+    fn language_tags_are_normalized() {
+        assert_eq!(language_name("en-US").as_deref(), Some("English"));
+        assert_eq!(language_name("pt_BR").as_deref(), Some("Portuguese"));
+        assert_eq!(language_name(" DE ").as_deref(), Some("German"));
+        assert_eq!(language_name("eo").as_deref(), Some("EO"));
+        assert_eq!(language_name(""), None);
+        assert_eq!(language_name("x1-y"), None);
+    }
+
+    #[test]
+    fn languages_are_only_shown_when_turned_on() {
+        let reply_to = message_event(serde_json::json!({
+            "msgtype": "m.text",
+            "body": "https://example.fr/grenouilles",
+        }));
+        let mut embed = Embed::new("Les grenouilles".to_string(), "Tout".to_string());
+        embed.site_name = Some("Grenouilles".to_string());
+        embed.language = Some("French".to_string());
+
+        let (body, _) = text_bodies(&embed.to_message_content(&Config::default(), &reply_to));
+        assert!(!body.contains("French"));
+
+        let mut config = Config::default();
+        config.embed_show_language = true;
+        let (body, formatted) = text_bodies(&embed.to_message_content(&config, &reply_to));
+        assert!(body.contains("via Grenouilles · French\n"));
+        assert!(formatted.contains("<sub>via Grenouilles · French</sub>"));
+    }
+
+    #[test]
+    fn json_ld_is_used_when_its_the_only_metadata() {
+        let page = r#"<html><head>
+            <script type="application/ld+json">
+                {"@context": "https://schema.org", "@type": "NewsArticle",
+                 "headline": "Frog Found Singing", "description": "A frog was heard singing."}
+            </script>
+        </head><body></body></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frog Found Singing");
+        assert_eq!(embed.description, "A frog was heard singing.");
+    }
+
+    #[test]
+    fn json_ld_arrays_and_graphs_are_searched() {
+        let page = r#"<html><head>
+            <script type="application/ld+json">
+                [{"@type": "Organization", "name": "Frog News"},
+                 {"@type": "Recipe", "name": "Frog Cake", "description": "Not made of frogs."}]
+            </script>
+        </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frog Cake");
+        assert_eq!(embed.description, "Not made of frogs.");
+
+        let page = r#"<html><head>
+            <script type="application/ld+json">
+                {"@context": "https://schema.org", "@graph": [
+                    {"@type": "WebSite", "name": "Frog News"},
+                    {"@type": ["Person"], "name": "Some Frog"},
+                    {"@type": "Article", "headline": "Pond Reopens", "description": "It's wet again."}
+                ]}
+            </script>
+        </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Pond Reopens");
+        assert_eq!(embed.description, "It's wet again.");
+    }
+
+    #[test]
+    fn json_ld_beats_meta_tags_and_bad_blocks_are_skipped() {
+        let page = r#"<html><head>
+            <meta property="og:title" content="Frogs | Frog News">
+            <meta property="og:description" content="Meta description">
+            <script type="application/ld+json">{ not json at all</script>
+            <script type="application/ld+json">
+                {"@type": "Article", "headline": "Frogs, In Depth"}
+            </script>
+        </head></html>"#;
+        let embed = parse_metadata(page).unwrap();
+        assert_eq!(embed.title, "Frogs, In Depth");
+        assert_eq!(embed.description, "Meta description");
+
+        // Nothing about the page itself, so the meta tags are used
+        let page = r#"<html><head>
+            <meta property="og:title" content="Frogs">
+            <script type="application/ld+json">{"@type": "Organization", "name": "Frog News"}</script>
+        </head></html>"#;
+        assert_eq!(parse_metadata(page).unwrap().title, "Frogs");
+    }
+
+    #[tokio::test]
+    async fn routine_embed_logs_are_not_warnings() {
+        record_logs();
+        let url = "https://example.com/log-levels";
+        let fetcher = FakeFetcher {
+            pages: HashMap::from([
+                (url.to_string(), FROG_PAGE.to_string()),
+                (format!("{}-bare", url), "<html></html>".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let config = Config::default();
+
+        get_embed(&fetcher, url, &config).await.unwrap();
+        get_embed(&fetcher, url, &config).await.unwrap();
+        assert_eq!(
+            logged_levels(&format!("Using cached embed for URL: '{}'", url)),
+            [log::Level::Debug]
+        );
+
+        let bare_url = format!("{}-bare", url);
+        get_embed(&fetcher, &bare_url, &config).await.unwrap();
+        assert_eq!(
+            logged_levels(&format!(
+                "Couldn't get metadata for URL: '{}': {}",
+                bare_url,
+                EmbedParseError::NoMetadata
+            )),
+            [log::Level::Info]
+        );
+    }
 }
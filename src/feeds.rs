@@ -0,0 +1,332 @@
+//! # The Feeds Module
+//!
+//! This module polls RSS and Atom feeds and posts their new entries to rooms.
+
+use crate::{embeds, retry_rate_limited, store, Config};
+use feed_rs::model::{Entry, Feed};
+use log::{debug, info, warn};
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId},
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+use std::{collections::HashMap, time::Duration};
+
+/// The name of the file the last seen entry of each feed is saved to in the store directory.
+const FEEDS_FILE: &str = "feeds.json";
+
+/// The most entries posted from one feed in a single poll, so a feed that comes back after a long
+/// time doesn't flood the room.
+const MAX_NEW_ENTRIES: usize = 5;
+
+/// A feed to poll and the room its new entries get posted to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeedConfig {
+    /// The URL of the RSS or Atom feed
+    pub url: String,
+    /// The room new entries get posted to
+    pub room_id: OwnedRoomId,
+}
+
+/// Parses the contents of an RSS or Atom feed.
+pub fn parse_feed(body: &[u8]) -> anyhow::Result<Feed> {
+    Ok(feed_rs::parser::parse(body)?)
+}
+
+/// Gets the entries of `feed` that are newer than the one with the ID `last_seen`, oldest first.
+///
+/// Entries are ordered newest first by their publish date when they have one, otherwise the order
+/// of the feed is kept. If `last_seen` isn't in the feed anymore, only the newest
+/// [`MAX_NEW_ENTRIES`] entries are returned.
+pub fn new_entries<'a>(feed: &'a Feed, last_seen: &str) -> Vec<&'a Entry> {
+    let mut entries: Vec<&Entry> = feed.entries.iter().collect();
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.published.or(entry.updated)));
+
+    let mut new: Vec<&Entry> = entries
+        .into_iter()
+        .take_while(|entry| entry.id != last_seen)
+        .take(MAX_NEW_ENTRIES)
+        .collect();
+    new.reverse();
+    new
+}
+
+/// Gets the ID of the newest entry in `feed`, if it has any.
+fn newest_entry_id(feed: &Feed) -> Option<&str> {
+    feed.entries
+        .iter()
+        .enumerate()
+        // Ties keep the entry that comes first in the feed, like `new_entries` does
+        .max_by_key(|(i, entry)| (entry.published.or(entry.updated), std::cmp::Reverse(*i)))
+        .map(|(_, entry)| entry.id.as_str())
+}
+
+/// Posts new entries of the configured feeds, forever.
+///
+/// This runs alongside the sync loop. The first time a feed is polled nothing gets posted, its
+/// newest entry is only remembered so old entries aren't posted all at once.
+pub async fn run_poller(client: &Client, config: &Config, http_client: &reqwest::Client) {
+    if config.feeds.is_empty() {
+        return std::future::pending().await;
+    }
+
+    let mut last_seen: HashMap<String, String> =
+        match store::load(config.store_path.as_deref(), FEEDS_FILE) {
+            Ok(last_seen) => last_seen.unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to load the last seen feed entries: {}", e);
+                HashMap::new()
+            }
+        };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(
+        config.feed_poll_interval_mins.max(1) * 60,
+    ));
+    loop {
+        interval.tick().await;
+        for feed_config in &config.feeds {
+            if let Err(e) = poll(client, http_client, config, feed_config, &mut last_seen).await {
+                warn!("Failed to poll feed: '{}': {}", feed_config.url, e);
+            }
+        }
+
+        if let Err(e) = store::save(config.store_path.as_deref(), FEEDS_FILE, &last_seen) {
+            warn!("Failed to save the last seen feed entries: {}", e);
+        }
+    }
+}
+
+/// Fetches a feed once, and posts any entries that are newer than the last one seen.
+async fn poll(
+    client: &Client,
+    http_client: &reqwest::Client,
+    config: &Config,
+    feed_config: &FeedConfig,
+    last_seen: &mut HashMap<String, String>,
+) -> anyhow::Result<()> {
+    debug!("Polling feed: '{}'", feed_config.url);
+    let res = http_client
+        .get(&feed_config.url)
+        .send()
+        .await?
+        .error_for_status()?;
+    // A feed that's been cut off isn't valid XML anymore, so there's no point parsing it
+    let (body, truncated) = embeds::read_body(res, config.feed_max_body_size).await?;
+    if truncated {
+        anyhow::bail!(
+            "feed is over the limit of {} bytes",
+            config.feed_max_body_size
+        );
+    }
+    let feed = parse_feed(&body)?;
+    let Some(newest) = newest_entry_id(&feed) else {
+        return Ok(());
+    };
+
+    let Some(previous) = last_seen.get(&feed_config.url) else {
+        info!("Started following feed: '{}'", feed_config.url);
+        last_seen.insert(feed_config.url.clone(), newest.to_string());
+        return Ok(());
+    };
+
+    let entries = new_entries(&feed, previous);
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let Some(room) = client.get_joined_room(&feed_config.room_id) else {
+        anyhow::bail!("frogbot isn't in room: '{}'", feed_config.room_id);
+    };
+    let feed_title = feed.title.as_ref().map(|title| title.content.as_str());
+    for entry in entries {
+        info!("Posting new entry of feed: '{}'", feed_config.url);
+        let content = entry_message_content(feed_title, entry);
+        retry_rate_limited(|| room.send(content.clone(), None)).await?;
+        // Remember each entry as soon as it's posted, so a failure can't post it twice
+        last_seen.insert(feed_config.url.clone(), entry.id.clone());
+    }
+    Ok(())
+}
+
+/// Builds the message posted for a new feed entry.
+fn entry_message_content(feed_title: Option<&str>, entry: &Entry) -> RoomMessageEventContent {
+    let title = entry
+        .title
+        .as_ref()
+        .map(|title| title.content.trim())
+        .filter(|title| !title.is_empty())
+        .unwrap_or("New entry");
+    let link = entry.links.first().map(|link| link.href.as_str());
+    let prefix = match feed_title {
+        Some(feed_title) => format!("📰 {}: ", feed_title),
+        None => "📰 ".to_string(),
+    };
+
+    match link {
+        Some(link) => RoomMessageEventContent::text_html(
+            format!("{}{} {}", prefix, title, link),
+            format!(
+                "{}<a href=\"{}\">{}</a>",
+                html_escape::encode_text(&prefix),
+                html_escape::encode_double_quoted_attribute(link),
+                html_escape::encode_text(title)
+            ),
+        ),
+        None => RoomMessageEventContent::text_plain(format!("{}{}", prefix, title)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{local_client, serve, FakeHomeserver};
+    use hyper::{Body, Response};
+
+    /// Builds an RSS feed with an item for each ID and publish date in `items`.
+    fn rss_feed(items: &[(&str, &str)]) -> Vec<u8> {
+        let items: String = items
+            .iter()
+            .map(|(id, published)| {
+                format!(
+                    "<item><title>Frog {id}</title><link>https://example.com/{id}</link>\
+                     <guid>{id}</guid><pubDate>{published}</pubDate></item>"
+                )
+            })
+            .collect();
+        format!(
+            "<?xml version=\"1.0\"?><rss version=\"2.0\"><channel><title>Frog News</title>\
+             <link>https://example.com</link><description>Frogs</description>{}</channel></rss>",
+            items
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn only_new_entries_are_found() {
+        let first_poll = parse_feed(&rss_feed(&[("1", "Mon, 02 Jan 2023 10:00:00 GMT")])).unwrap();
+        let last_seen = newest_entry_id(&first_poll).unwrap().to_string();
+        assert_eq!(last_seen, "1");
+        assert!(new_entries(&first_poll, &last_seen).is_empty());
+
+        let second_poll = parse_feed(&rss_feed(&[
+            ("2", "Tue, 03 Jan 2023 10:00:00 GMT"),
+            ("1", "Mon, 02 Jan 2023 10:00:00 GMT"),
+        ]))
+        .unwrap();
+        let new: Vec<&str> = new_entries(&second_poll, &last_seen)
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect();
+        assert_eq!(new, ["2"]);
+        assert_eq!(newest_entry_id(&second_poll), Some("2"));
+    }
+
+    #[test]
+    fn lost_feeds_only_post_the_newest_entries() {
+        let items: Vec<(String, String)> = (1..=9)
+            .map(|day| (day.to_string(), format!("{:02} Jan 2023 10:00:00 GMT", day)))
+            .collect();
+        let items: Vec<(&str, &str)> = items
+            .iter()
+            .map(|(id, published)| (id.as_str(), published.as_str()))
+            .collect();
+        let feed = parse_feed(&rss_feed(&items)).unwrap();
+
+        let new: Vec<&str> = new_entries(&feed, "gone")
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect();
+        assert_eq!(new, ["5", "6", "7", "8", "9"]);
+    }
+
+    #[test]
+    fn entries_are_posted_with_their_link() {
+        let feed = parse_feed(&rss_feed(&[("1", "Mon, 02 Jan 2023 10:00:00 GMT")])).unwrap();
+        let content = entry_message_content(Some("Frog News"), &feed.entries[0]);
+        let content = serde_json::to_value(&content).unwrap();
+        assert_eq!(
+            content["body"],
+            "📰 Frog News: Frog 1 https://example.com/1"
+        );
+        assert_eq!(
+            content["formatted_body"],
+            "📰 Frog News: <a href=\"https://example.com/1\">Frog 1</a>"
+        );
+    }
+
+    #[tokio::test]
+    async fn feeds_over_the_size_limit_are_skipped() {
+        let feed = rss_feed(&[("1", "Mon, 02 Jan 2023 10:00:00 GMT")]);
+        let url = serve(move |_| {
+            let feed = feed.clone();
+            async move { Response::new(Body::from(feed)) }
+        })
+        .await;
+        let homeserver = FakeHomeserver::start(vec![]).await;
+        let client = homeserver.client().await;
+        let feed_config = FeedConfig {
+            url,
+            room_id: "!room:example.com".try_into().unwrap(),
+        };
+        let mut config = Config::default();
+        config.feed_max_body_size = 64;
+        let mut last_seen = HashMap::new();
+
+        let error = poll(
+            &client,
+            &local_client(),
+            &config,
+            &feed_config,
+            &mut last_seen,
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("over the limit"));
+        assert!(last_seen.is_empty());
+
+        config.feed_max_body_size = 1024 * 1024;
+        poll(
+            &client,
+            &local_client(),
+            &config,
+            &feed_config,
+            &mut last_seen,
+        )
+        .await
+        .unwrap();
+        assert_eq!(last_seen[&feed_config.url], "1");
+    }
+
+    #[tokio::test]
+    async fn endless_feeds_are_cut_off() {
+        // Without a Content-Length the feed has to be read before it's known to be too big
+        let url = serve(|_| async {
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                while sender.send_data(vec![b'a'; 1024].into()).await.is_ok() {}
+            });
+            Response::new(body)
+        })
+        .await;
+        let homeserver = FakeHomeserver::start(vec![]).await;
+        let client = homeserver.client().await;
+        let feed_config = FeedConfig {
+            url,
+            room_id: "!room:example.com".try_into().unwrap(),
+        };
+        let mut config = Config::default();
+        config.feed_max_body_size = 8 * 1024;
+
+        let error = poll(
+            &client,
+            &local_client(),
+            &config,
+            &feed_config,
+            &mut HashMap::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("over the limit"));
+    }
+}
@@ -0,0 +1,130 @@
+//! # The Health Module
+//!
+//! This module serves a health check endpoint, for container orchestrators and uptime monitors,
+//! along with metrics for Prometheus.
+
+use crate::{metrics, Status};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use log::info;
+use serde::Serialize;
+
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+/// What `/health` responds with.
+#[derive(Serialize)]
+struct HealthResponse {
+    /// Whether frogbot has logged in and synced with the homeserver
+    ready: bool,
+    /// How many seconds frogbot has been running for
+    uptime_secs: u64,
+    /// How many seconds ago the last sync finished, if one has yet
+    last_sync_secs_ago: Option<u64>,
+}
+
+/// Serves the health check and metrics endpoints on `port`, until the server fails.
+///
+/// `GET /health` responds with 200 once frogbot has logged in and finished its first sync, and
+/// with 503 before then. `GET /metrics` responds with the counters from [`metrics`].
+pub async fn serve(port: u16, status: Arc<Status>) -> anyhow::Result<()> {
+    let address = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let make_service = make_service_fn(move |_| {
+        let status = status.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let status = status.clone();
+                async move { Ok::<_, Infallible>(respond(&request, &status)) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&address)?.serve(make_service);
+    info!("Serving health checks on: '{}'", address);
+    server.await?;
+    Ok(())
+}
+
+/// Builds the response to a request to the health server.
+fn respond(request: &Request<Body>, status: &Status) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/health") => health(status),
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render()))
+            .unwrap_or_default(),
+        _ => plain_response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// Reports whether frogbot is ready, along with when it last synced.
+fn health(status: &Status) -> Response<Body> {
+    let last_sync = status.last_sync();
+    let health = HealthResponse {
+        ready: last_sync.is_some(),
+        uptime_secs: status.started.elapsed().as_secs(),
+        last_sync_secs_ago: last_sync.map(|last_sync| last_sync.elapsed().as_secs()),
+    };
+    let code = match health.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    match serde_json::to_string(&health) {
+        Ok(body) => Response::builder()
+            .status(code)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap_or_default(),
+        Err(_) => plain_response(StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+    }
+}
+
+/// Builds a plain text response.
+fn plain_response(code: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = code;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `GET path` to the health server, returning the status code and body.
+    async fn get(path: &str, status: &Status) -> (StatusCode, String) {
+        let request = Request::get(path).body(Body::empty()).unwrap();
+        let response = respond(&request, status);
+        let code = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (code, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn health_is_ready_after_the_first_sync() {
+        let status = Status::new();
+        let (code, body) = get("/health", &status).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(health["ready"], false);
+        assert_eq!(health["last_sync_secs_ago"], serde_json::Value::Null);
+
+        status.record_sync();
+        let (code, body) = get("/health", &status).await;
+        assert_eq!(code, StatusCode::OK);
+        let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(health["ready"], true);
+        assert_eq!(health["last_sync_secs_ago"], 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_are_not_found() {
+        let (code, _) = get("/frogs", &Status::new()).await;
+        assert_eq!(code, StatusCode::NOT_FOUND);
+    }
+}
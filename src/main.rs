@@ -1,9 +1,94 @@
-use frogbot::{run, Config};
+use frogbot::{
+    config_path_from_args,
+    reload::{LogLevelSetter, ReloadSource},
+    run, Config, LogFormat,
+};
+use tracing_subscriber::{fmt::format::FmtSpan, prelude::*, reload, EnvFilter};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // init logging
-    tracing_subscriber::fmt::init();
-    let config = Config::load("./config.toml");
-    run(config).await
+    // Prefer environment variables if they're there, for container and secrets manager setups
+    let (config, config_path) = if std::env::var_os("FROGBOT_HOMESERVER").is_some() {
+        (Config::from_env()?, None)
+    } else {
+        let config_path = config_path_from_args(std::env::args());
+        match Config::load(&config_path) {
+            Ok(config) => (config, Some(config_path)),
+            Err(e) => {
+                eprintln!("Couldn't load frogbot's config: {}", e);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    // init logging, RUST_LOG still wins so debugging doesn't need a config change
+    let rust_log = std::env::var(EnvFilter::DEFAULT_ENV).ok();
+    let (filter, log_level_reloadable) =
+        log_filter(rust_log.as_deref(), config.log_level.as_deref())?;
+    // The filter can be swapped out later, so a config reload can change the log level
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let set_log_level: Option<LogLevelSetter> = match log_level_reloadable {
+        true => Some(Box::new(move |log_level| {
+            filter_handle.reload(EnvFilter::try_new(log_level)?)?;
+            Ok(())
+        })),
+        false => None,
+    };
+    // Closing a span logs how long it took, which is how embed timings show up with debug logs on
+    let fmt_layer = tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE);
+    let subscriber = tracing_subscriber::registry().with(filter);
+    match config.log_format {
+        LogFormat::Plain => subscriber.with(fmt_layer).init(),
+        LogFormat::Json => subscriber.with(fmt_layer.json()).init(),
+    }
+
+    let reload_source = ReloadSource {
+        config_path,
+        set_log_level,
+    };
+    if let Err(e) = run(config, reload_source).await {
+        log::error!("frogbot stopped: {:#}", e);
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Builds the log filter from `rust_log` if it's set and valid, or from the config's `log_level`.
+///
+/// Also returns whether the filter came from the config, since only then can a reload change it.
+fn log_filter(
+    rust_log: Option<&str>,
+    log_level: Option<&str>,
+) -> anyhow::Result<(EnvFilter, bool)> {
+    match rust_log.map(EnvFilter::try_new) {
+        Some(Ok(filter)) => Ok((filter, false)),
+        _ => Ok((EnvFilter::try_new(log_level.unwrap_or("info"))?, true)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_filter_comes_from_the_config() {
+        let (filter, reloadable) = log_filter(None, Some("frogbot=debug")).unwrap();
+        assert_eq!(filter.to_string(), "frogbot=debug");
+        assert!(reloadable);
+
+        let (filter, _) = log_filter(None, None).unwrap();
+        assert_eq!(filter.to_string(), "info");
+    }
+
+    #[test]
+    fn rust_log_overrides_the_config() {
+        let (filter, reloadable) = log_filter(Some("warn"), Some("debug")).unwrap();
+        assert_eq!(filter.to_string(), "warn");
+        assert!(!reloadable);
+    }
+
+    #[test]
+    fn invalid_log_levels_are_errors() {
+        assert!(log_filter(None, Some("frogbot=loud")).is_err());
+    }
 }
@@ -0,0 +1,104 @@
+//! # The Metrics Module
+//!
+//! This module counts what frogbot does, and formats the counts for Prometheus to scrape.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A number that only goes up, like how many embeds have been sent.
+pub struct Counter {
+    /// The name Prometheus knows the counter by (e.g. "frogbot_embeds_sent_total")
+    name: &'static str,
+    /// What the counter counts
+    help: &'static str,
+    /// The count so far
+    value: AtomicU64,
+}
+
+impl Counter {
+    /// Creates a new [`Counter`], starting at zero.
+    const fn new(name: &'static str, help: &'static str) -> Counter {
+        Counter {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds one to the counter.
+    pub fn increment(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets the count so far.
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// Embeds sent as replies to links.
+pub static EMBEDS_SENT: Counter = Counter::new(
+    "frogbot_embeds_sent_total",
+    "Embeds sent as replies to links",
+);
+/// Links that couldn't be fetched for an embed.
+pub static EMBED_FETCH_FAILURES: Counter = Counter::new(
+    "frogbot_embed_fetch_failures_total",
+    "Links that couldn't be fetched for an embed",
+);
+/// Commands run, whether they worked or not.
+pub static COMMANDS_HANDLED: Counter =
+    Counter::new("frogbot_commands_handled_total", "Commands run");
+/// Syncs with the homeserver that failed.
+pub static SYNC_ERRORS: Counter = Counter::new(
+    "frogbot_sync_errors_total",
+    "Syncs with the homeserver that failed",
+);
+
+/// Every counter, in the order they're listed on `/metrics`.
+static COUNTERS: &[&Counter] = &[
+    &EMBEDS_SENT,
+    &EMBED_FETCH_FAILURES,
+    &COMMANDS_HANDLED,
+    &SYNC_ERRORS,
+];
+
+/// Formats every counter in the Prometheus text format.
+pub fn render() -> String {
+    let mut metrics = String::new();
+    for counter in COUNTERS {
+        // Writing to a String can't fail
+        let _ = writeln!(metrics, "# HELP {} {}", counter.name, counter.help);
+        let _ = writeln!(metrics, "# TYPE {} counter", counter.name);
+        let _ = writeln!(metrics, "{} {}", counter.name, counter.get());
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_count_up() {
+        let counter = Counter::new("frogbot_test_total", "A test counter");
+        assert_eq!(counter.get(), 0);
+        counter.increment();
+        counter.increment();
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn every_counter_is_rendered() {
+        let metrics = render();
+        for counter in COUNTERS {
+            assert!(metrics.contains(&format!("# HELP {} {}\n", counter.name, counter.help)));
+            assert!(metrics.contains(&format!("# TYPE {} counter\n", counter.name)));
+        }
+        assert!(metrics
+            .lines()
+            .any(|line| line.starts_with("frogbot_sync_errors_total ")));
+    }
+}
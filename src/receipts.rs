@@ -0,0 +1,119 @@
+//! # The Receipts Module
+//!
+//! This module sends read receipts for the messages frogbot has handled.
+
+use crate::Config;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use matrix_sdk::{
+    ruma::{EventId, OwnedEventId, OwnedRoomId, RoomId},
+    Client,
+};
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+/// How often read receipts get sent.
+///
+/// Receipts are only sent for the latest message in each room, so a busy room gets one receipt
+/// per interval instead of one per message.
+const SEND_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// The latest handled message in each room that hasn't had a receipt sent for it yet
+    static ref UNREAD: Mutex<HashMap<OwnedRoomId, OwnedEventId>> = Mutex::new(HashMap::new());
+}
+
+/// Records that frogbot handled a message, so a read receipt gets sent for it.
+pub fn mark_handled(config: &Config, room_id: &RoomId, event_id: &EventId) {
+    if !config.send_read_receipts {
+        return;
+    }
+    UNREAD
+        .lock()
+        .unwrap()
+        .insert(room_id.to_owned(), event_id.to_owned());
+}
+
+/// Sends read receipts for handled messages, forever.
+///
+/// This runs alongside the sync loop, and does nothing if read receipts are turned off.
+pub async fn run_sender(client: &Client, config: &Config) {
+    if !config.send_read_receipts {
+        return std::future::pending().await;
+    }
+
+    let mut interval = tokio::time::interval(SEND_INTERVAL);
+    loop {
+        interval.tick().await;
+        let unread = std::mem::take(&mut *UNREAD.lock().unwrap());
+        for (room_id, event_id) in unread {
+            let Some(room) = client.get_joined_room(&room_id) else {
+                continue;
+            };
+            debug!("Sending read receipt in room: '{}'", room_id);
+            if let Err(e) = room.read_receipt(&event_id).await {
+                warn!("Failed to send read receipt in room: '{}': {}", room_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        embeds::embed_handler,
+        testing::{bot_state, FakeFetcher, FakeHomeserver},
+    };
+    use matrix_sdk::event_handler::Ctx;
+
+    #[tokio::test]
+    async fn receipts_are_sent_for_the_latest_handled_message() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let client = homeserver.client().await;
+        let room_id = <&RoomId>::try_from("!room:example.com").unwrap();
+        let mut config = Config::default();
+
+        mark_handled(
+            &config,
+            room_id,
+            <&EventId>::try_from("$ignored:example.com").unwrap(),
+        );
+        assert!(UNREAD.lock().unwrap().is_empty());
+
+        config.send_read_receipts = true;
+        mark_handled(
+            &config,
+            room_id,
+            <&EventId>::try_from("$first:example.com").unwrap(),
+        );
+        mark_handled(
+            &config,
+            room_id,
+            <&EventId>::try_from("$second:example.com").unwrap(),
+        );
+        // frogbot's own messages, like its embeds, aren't handled so they don't get receipts
+        let own_message = serde_json::from_value(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$own:example.com",
+            "sender": "@frogbot:example.com",
+            "origin_server_ts": 1_700_000_000_000u64,
+            "content": { "msgtype": "m.text", "body": "Frogs\nAll about frogs" },
+        }))
+        .unwrap();
+        embed_handler(
+            own_message,
+            client.get_room(room_id).unwrap(),
+            client.clone(),
+            Ctx(bot_state(config.clone(), FakeFetcher::default())),
+        )
+        .await;
+        // The first receipts go out straight away, so this is plenty of time
+        let _ =
+            tokio::time::timeout(Duration::from_millis(500), run_sender(&client, &config)).await;
+
+        let receipts = homeserver.requests("POST", "/receipt/m.read/");
+        assert_eq!(receipts.len(), 1);
+        assert!(receipts[0].method_and_path.contains("second"));
+    }
+}
@@ -0,0 +1,290 @@
+//! # The Reload Module
+//!
+//! This module reloads frogbot's config when it gets a SIGHUP, so settings can be changed without
+//! restarting and dropping the sync connection.
+
+use crate::Config;
+use log::{error, info, warn};
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, RwLock},
+};
+
+/// The settings that take effect on a reload.
+///
+/// These are all read fresh for every message, so changing them mid-run is safe. Everything else
+/// is only read at startup, so changing it needs a restart. [`apply_reloadable`] has to copy over
+/// exactly these.
+const RELOADABLE_FIELDS: &[&str] = &[
+    "embed_domain_allowlist",
+    "embed_domain_blocklist",
+    "embed_tracking_params",
+    "max_embeds_per_message",
+    "embeds_enabled_by_default",
+    "reply_on_no_metadata",
+    "embed_typing_notice",
+    "embed_opt_out_marker",
+    "embed_images",
+    "embed_show_language",
+    "admins",
+    "ignored_senders",
+    "invite_allowlist",
+    "command_prefix",
+    "command_cooldown_secs",
+    "log_level",
+];
+
+/// Changes the log level while frogbot is running, given a filter like "info" or "frogbot=debug".
+pub type LogLevelSetter = Box<dyn Fn(&str) -> anyhow::Result<()> + Send + Sync>;
+
+/// Where frogbot's config came from, so it can be read again on SIGHUP.
+#[derive(Default)]
+pub struct ReloadSource {
+    /// The config file, or `None` if the config came from environment variables
+    pub config_path: Option<String>,
+    /// Applies a new `log_level`, or `None` if the log level can't be changed (e.g. RUST_LOG is set)
+    pub set_log_level: Option<LogLevelSetter>,
+}
+
+/// The config event handlers read from, which gets swapped for a new one on reload.
+///
+/// Handlers take a snapshot with [`LiveConfig::get`] when an event comes in, so one event is
+/// always handled with one version of the config, even if a reload happens halfway through.
+#[derive(Clone)]
+pub struct LiveConfig(Arc<RwLock<Arc<Config>>>);
+
+impl LiveConfig {
+    /// Creates a new [`LiveConfig`], starting with `config`.
+    pub fn new(config: Arc<Config>) -> LiveConfig {
+        LiveConfig(Arc::new(RwLock::new(config)))
+    }
+
+    /// Gets the current config.
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Applies the reloadable settings from `new`, keeping the rest of the current config.
+    ///
+    /// Returns the names of the settings that changed but need a restart to take effect.
+    pub fn reload(&self, new: Config) -> Vec<String> {
+        let mut live = self.0.write().unwrap();
+        let restart_needed = restart_needed_fields(&live, &new);
+        let mut config = Config::clone(&live);
+        apply_reloadable(&mut config, new);
+        *live = Arc::new(config);
+        restart_needed
+    }
+}
+
+/// Copies the settings in [`RELOADABLE_FIELDS`] from `new` into `config`.
+fn apply_reloadable(config: &mut Config, new: Config) {
+    config.embed_domain_allowlist = new.embed_domain_allowlist;
+    config.embed_domain_blocklist = new.embed_domain_blocklist;
+    config.embed_tracking_params = new.embed_tracking_params;
+    config.max_embeds_per_message = new.max_embeds_per_message;
+    config.embeds_enabled_by_default = new.embeds_enabled_by_default;
+    config.reply_on_no_metadata = new.reply_on_no_metadata;
+    config.embed_typing_notice = new.embed_typing_notice;
+    config.embed_opt_out_marker = new.embed_opt_out_marker;
+    config.embed_images = new.embed_images;
+    config.embed_show_language = new.embed_show_language;
+    config.admins = new.admins;
+    config.ignored_senders = new.ignored_senders;
+    config.invite_allowlist = new.invite_allowlist;
+    config.command_prefix = new.command_prefix;
+    config.command_cooldown_secs = new.command_cooldown_secs;
+    config.log_level = new.log_level;
+}
+
+/// Finds the settings that differ between `current` and `new` but can't be reloaded.
+///
+/// Only the names are returned, so secrets like the password never end up in the logs.
+fn restart_needed_fields(current: &Config, new: &Config) -> Vec<String> {
+    let (Ok(toml::Value::Table(current)), Ok(toml::Value::Table(new))) =
+        (toml::Value::try_from(current), toml::Value::try_from(new))
+    else {
+        return Vec::new();
+    };
+
+    current
+        .keys()
+        .chain(new.keys())
+        .filter(|field| !RELOADABLE_FIELDS.contains(&field.as_str()))
+        .filter(|field| current.get(*field) != new.get(*field))
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Reloads the config every time frogbot gets a SIGHUP, forever.
+///
+/// This runs alongside the sync loop. A config that fails to load or validate is logged and
+/// ignored, so a typo doesn't take frogbot down.
+pub async fn run_reloader(live_config: &LiveConfig, source: &ReloadSource) {
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(mut sighup) => {
+            while sighup.recv().await.is_some() {
+                info!("Got SIGHUP, reloading config");
+                reload_config(live_config, source);
+            }
+        }
+        Err(e) => error!(
+            "Failed to listen for SIGHUP, so the config can't be reloaded: {}",
+            e
+        ),
+    }
+
+    std::future::pending::<()>().await;
+}
+
+/// Reads the config again and applies what can be applied without restarting.
+fn reload_config(live_config: &LiveConfig, source: &ReloadSource) {
+    let Some(config_path) = &source.config_path else {
+        warn!("The config came from environment variables, so there's no config file to reload");
+        return;
+    };
+    let new = match Config::load(config_path) {
+        Ok(new) => new,
+        Err(e) => {
+            error!("Couldn't reload config, keeping the current one: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = new.validate() {
+        error!("Couldn't reload config, keeping the current one: {}", e);
+        return;
+    }
+
+    let old_log_level = live_config.get().log_level.clone();
+    for field in live_config.reload(new) {
+        warn!("'{}' changed, restart frogbot to apply it", field);
+    }
+
+    let log_level = live_config.get().log_level.clone();
+    if log_level != old_log_level {
+        let log_level = log_level.as_deref().unwrap_or("info");
+        match &source.set_log_level {
+            Some(set_log_level) => match set_log_level(log_level) {
+                Ok(()) => info!("Changed log level to: '{}'", log_level),
+                Err(e) => error!("Failed to change log level: {}", e),
+            },
+            None => warn!("log_level changed, but RUST_LOG is set so it has no effect"),
+        }
+    }
+    info!("Reloaded config");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_dir, valid_config};
+    use std::sync::Mutex;
+
+    #[test]
+    fn reloadable_settings_are_applied() {
+        let live_config = LiveConfig::new(Arc::new(valid_config()));
+        let before = live_config.get();
+
+        let mut new = valid_config();
+        new.embed_domain_blocklist = vec!["example.org".to_string()];
+        new.command_prefix = "?".to_string();
+        assert!(live_config.reload(new).is_empty());
+
+        let after = live_config.get();
+        assert_eq!(
+            after.embed_domain_blocklist,
+            vec!["example.org".to_string()]
+        );
+        assert_eq!(after.command_prefix, "?");
+        // Snapshots taken before the reload keep the config they were taken with
+        assert!(before.embed_domain_blocklist.is_empty());
+    }
+
+    #[test]
+    fn other_settings_need_a_restart() {
+        let live_config = LiveConfig::new(Arc::new(valid_config()));
+
+        let mut new = valid_config();
+        new.homeserver = "https://other.example.com".to_string();
+        new.password = "hunter3".to_string();
+        new.embed_images = true;
+
+        assert_eq!(live_config.reload(new), vec!["homeserver", "password"]);
+        let after = live_config.get();
+        assert_eq!(after.homeserver, "https://matrix.example.com");
+        assert_eq!(after.password, "hunter2");
+        assert!(after.embed_images);
+    }
+
+    #[test]
+    fn reloadable_fields_are_all_real_settings() {
+        // Unset options are left out, so set them all
+        let mut config = valid_config();
+        config.embed_domain_allowlist = Some(vec![]);
+        config.invite_allowlist = Some(vec![]);
+        config.log_level = Some("info".to_string());
+        let toml::Value::Table(config) = toml::Value::try_from(config).unwrap() else {
+            panic!("the config should serialize to a table");
+        };
+        for field in RELOADABLE_FIELDS {
+            assert!(
+                config.contains_key(*field),
+                "'{}' isn't a config setting",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn config_files_are_read_again() {
+        let dir = test_dir("reload");
+        let config_path = dir.join("config.toml");
+        let write_config = |extra: &str| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    r#"homeserver = "https://matrix.example.com"
+username = "frogbot"
+display_name = "frogbot"
+password = "hunter2"
+room_ids = []
+{}"#,
+                    extra
+                ),
+            )
+            .unwrap();
+        };
+        let log_levels = Arc::new(Mutex::new(Vec::new()));
+        let source = ReloadSource {
+            config_path: Some(config_path.to_str().unwrap().to_string()),
+            set_log_level: Some({
+                let log_levels = log_levels.clone();
+                Box::new(move |level: &str| {
+                    log_levels.lock().unwrap().push(level.to_string());
+                    Ok(())
+                })
+            }),
+        };
+        let live_config = LiveConfig::new(Arc::new(valid_config()));
+
+        write_config("embed_domain_blocklist = [\"example.org\"]\nlog_level = \"debug\"\n");
+        reload_config(&live_config, &source);
+        assert_eq!(
+            live_config.get().embed_domain_blocklist,
+            vec!["example.org".to_string()]
+        );
+        assert_eq!(*log_levels.lock().unwrap(), vec!["debug".to_string()]);
+
+        // Broken configs are ignored
+        write_config("embed_domain_blocklist = \"not a list\"\n");
+        reload_config(&live_config, &source);
+        assert_eq!(
+            live_config.get().embed_domain_blocklist,
+            vec!["example.org".to_string()]
+        );
+    }
+}
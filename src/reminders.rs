@@ -0,0 +1,222 @@
+//! # The Reminders Module
+//!
+//! This module keeps track of reminders set with `!remindme` and posts them when they're due.
+
+use crate::{retry_rate_limited, store, Config};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use matrix_sdk::{
+    ruma::{
+        events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, RoomId, UserId,
+    },
+    Client,
+};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// The name of the file pending reminders are saved to in the store directory.
+const REMINDERS_FILE: &str = "reminders.json";
+
+/// The longest a reminder can be set for.
+const MAX_REMINDER_DELAY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// How often to check for reminders that are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A reminder that's waiting to be posted.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Reminder {
+    /// The room to post the reminder in
+    pub room_id: OwnedRoomId,
+    /// Who asked to be reminded
+    pub user_id: OwnedUserId,
+    /// What to remind them about
+    pub message: String,
+    /// When the reminder should be posted
+    pub due: SystemTime,
+}
+
+lazy_static! {
+    /// Every reminder that hasn't been posted yet
+    static ref PENDING: Mutex<Vec<Reminder>> = Mutex::new(vec![]);
+}
+
+/// Parses a duration like `10s`, `5m`, `2h`, `1d`, or a combination like `1h30m`.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = duration;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let amount: u64 = rest[..digits].parse().ok()?;
+        let unit = rest[digits..].chars().next()?;
+        let secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(Duration::from_secs(amount.checked_mul(secs)?))?;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+
+    if total.is_zero() || total > MAX_REMINDER_DELAY {
+        return None;
+    }
+    Some(total)
+}
+
+/// Loads the reminders saved by a previous run of frogbot, so they still get posted.
+pub fn load(config: &Config) -> anyhow::Result<()> {
+    let reminders: Vec<Reminder> =
+        store::load(config.store_path.as_deref(), REMINDERS_FILE)?.unwrap_or_default();
+    info!("Loaded {} pending reminders", reminders.len());
+    *PENDING.lock().unwrap() = reminders;
+    Ok(())
+}
+
+/// Adds a reminder for `user_id` in `room_id`, `delay` from now.
+pub fn add(
+    config: &Config,
+    room_id: &RoomId,
+    user_id: &UserId,
+    message: &str,
+    delay: Duration,
+) -> anyhow::Result<()> {
+    // Save while still holding the lock, so two changes can't save out of order
+    let mut pending = PENDING.lock().unwrap();
+    pending.push(Reminder {
+        room_id: room_id.to_owned(),
+        user_id: user_id.to_owned(),
+        message: message.to_string(),
+        due: SystemTime::now() + delay,
+    });
+    store::save(config.store_path.as_deref(), REMINDERS_FILE, &*pending)
+}
+
+/// Takes every reminder that's due out of the pending list.
+fn take_due() -> Vec<Reminder> {
+    let now = SystemTime::now();
+    let mut pending = PENDING.lock().unwrap();
+    let (due, not_due) = pending.drain(..).partition(|reminder| reminder.due <= now);
+    *pending = not_due;
+    due
+}
+
+/// Posts reminders as they come due, forever.
+///
+/// This runs alongside the sync loop. Reminders that came due while frogbot was down get posted
+/// as soon as it's back.
+pub async fn run_scheduler(client: &Client, config: &Config) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let due = take_due();
+        if due.is_empty() {
+            continue;
+        }
+
+        for reminder in due {
+            if let Err(e) = post(client, &reminder).await {
+                warn!(
+                    "Failed to post reminder in room: '{}': {}",
+                    reminder.room_id, e
+                );
+            }
+        }
+
+        let pending = PENDING.lock().unwrap();
+        if let Err(e) = store::save(config.store_path.as_deref(), REMINDERS_FILE, &*pending) {
+            warn!("Failed to save reminders: {}", e);
+        }
+    }
+}
+
+/// Posts a reminder, pinging the user who asked for it.
+async fn post(client: &Client, reminder: &Reminder) -> anyhow::Result<()> {
+    let Some(room) = client.get_joined_room(&reminder.room_id) else {
+        anyhow::bail!("frogbot isn't in the room anymore");
+    };
+
+    let content = RoomMessageEventContent::text_html(
+        format!("{}: ⏰ {}", reminder.user_id, reminder.message),
+        format!(
+            "<a href=\"https://matrix.to/#/{}\">{}</a>: ⏰ {}",
+            reminder.user_id,
+            reminder.user_id,
+            html_escape::encode_text(&reminder.message)
+        ),
+    );
+    info!("Posting reminder for: '{}'", reminder.user_id);
+    retry_rate_limited(|| room.send(content.clone(), None)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_dir, FakeHomeserver};
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("10s"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(
+            parse_duration("1d"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("1h30m"), Some(Duration::from_secs(90 * 60)));
+    }
+
+    #[test]
+    fn bad_durations_are_rejected() {
+        for duration in [
+            "",
+            "10",
+            "m",
+            "5x",
+            "0s",
+            "-5m",
+            "400d",
+            "99999999999999999999s",
+        ] {
+            assert_eq!(
+                parse_duration(duration),
+                None,
+                "{} should be rejected",
+                duration
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn short_reminders_fire() {
+        let homeserver = FakeHomeserver::with_rooms(&["!room:example.com"], vec![]).await;
+        let client = homeserver.client().await;
+        let mut config = Config::default();
+        config.store_path = Some(test_dir("reminders"));
+        let room_id = <&RoomId>::try_from("!room:example.com").unwrap();
+        let user_id = <&UserId>::try_from("@frog:example.com").unwrap();
+
+        add(
+            &config,
+            room_id,
+            user_id,
+            "take the pizza out",
+            Duration::from_millis(10),
+        )
+        .unwrap();
+        let _ = tokio::time::timeout(Duration::from_millis(1500), run_scheduler(&client, &config))
+            .await;
+
+        let sent = homeserver.sent_messages();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0]["body"], "@frog:example.com: ⏰ take the pizza out");
+        assert!(PENDING.lock().unwrap().is_empty());
+    }
+}
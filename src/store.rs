@@ -0,0 +1,54 @@
+//! # The Store Module
+//!
+//! This module saves frogbot's state into the store directory so it survives restarts.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+/// Loads the value saved as `name` in the store directory, if there is one.
+///
+/// Without a store directory nothing ever gets saved, so this always returns `None`.
+pub fn load<T: DeserializeOwned>(
+    store_path: Option<&Path>,
+    name: &str,
+) -> anyhow::Result<Option<T>> {
+    let Some(store_path) = store_path else {
+        return Ok(None);
+    };
+
+    let file = store_path.join(name);
+    if !file.exists() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(file)?;
+    Ok(Some(serde_json::from_str(&contents)?))
+}
+
+/// Saves `value` as `name` in the store directory, if there is one.
+///
+/// Some of what gets saved is secret (like the session's access token), so the files are only
+/// readable by their owner. The value is written to a temporary file first and then moved into
+/// place, so a crash halfway through can't leave a corrupted file behind.
+pub fn save<T: Serialize>(store_path: Option<&Path>, name: &str, value: &T) -> anyhow::Result<()> {
+    let Some(store_path) = store_path else {
+        return Ok(());
+    };
+
+    std::fs::create_dir_all(store_path)?;
+    let file = store_path.join(name);
+    let temp_file = store_path.join(format!("{}.tmp", name));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+        .open(&temp_file)?
+        .write_all(serde_json::to_string(value)?.as_bytes())?;
+
+    std::fs::rename(temp_file, file)?;
+    Ok(())
+}
@@ -0,0 +1,399 @@
+//! # The Testing Module
+//!
+//! This module has the fakes frogbot's tests run against instead of a real homeserver or the web.
+
+use crate::{
+    embeds::{ApiFuture, ApiRequest, FetchFuture, Image, ImageFuture, MetadataFetcher, Page},
+    reload::LiveConfig,
+    BotState, Config, Status,
+};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{events::room::message::OriginalRoomMessageEvent, UserId},
+    Client, Session,
+};
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A request the [`FakeHomeserver`] got.
+pub struct ReceivedRequest {
+    /// The request's method and path, like "GET /_matrix/client/v3/devices"
+    pub method_and_path: String,
+    /// The request's query string, if it had one
+    pub query: String,
+    /// The request's body
+    pub body: String,
+}
+
+/// A homeserver that answers with canned JSON, keeping track of every request it gets.
+pub struct FakeHomeserver {
+    /// The URL to reach it at
+    url: String,
+    /// The rooms the client is in once it syncs
+    joined_rooms: Vec<String>,
+    /// Every request it got, in order
+    requests: Arc<Mutex<Vec<ReceivedRequest>>>,
+}
+
+impl FakeHomeserver {
+    /// Starts a homeserver that answers requests matching one of `responses` with its JSON.
+    ///
+    /// Each response is matched by method and part of the path, like `("GET", "/devices")`.
+    /// Responses with an `errcode` are sent with the error status that goes with it. Anything
+    /// else gets the answer a quiet homeserver would give, or an empty object.
+    pub async fn start(responses: Vec<(&'static str, &'static str, serde_json::Value)>) -> Self {
+        FakeHomeserver::with_rooms(&[], responses).await
+    }
+
+    /// Starts a homeserver like [`FakeHomeserver::start`], whose syncs say the client is in
+    /// `joined_rooms`.
+    pub async fn with_rooms(
+        joined_rooms: &[&str],
+        responses: Vec<(&'static str, &'static str, serde_json::Value)>,
+    ) -> Self {
+        let joined_rooms: Vec<String> = joined_rooms.iter().map(|room| room.to_string()).collect();
+        let sync = serde_json::json!({
+            "next_batch": "s1",
+            "rooms": {
+                "join": joined_rooms
+                    .iter()
+                    .map(|room| (room.clone(), serde_json::json!({})))
+                    .collect::<serde_json::Map<_, _>>(),
+            },
+        });
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let responses = Arc::new(responses);
+        let make_service = {
+            let requests = requests.clone();
+            make_service_fn(move |_| {
+                let requests = requests.clone();
+                let responses = responses.clone();
+                let sync = sync.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        let requests = requests.clone();
+                        let responses = responses.clone();
+                        let sync = sync.clone();
+                        async move {
+                            let method = request.method().to_string();
+                            let path = request.uri().path().to_string();
+                            let query = request.uri().query().unwrap_or_default().to_string();
+                            let body = hyper::body::to_bytes(request.into_body())
+                                .await
+                                .unwrap_or_default();
+                            requests.lock().unwrap().push(ReceivedRequest {
+                                method_and_path: format!("{} {}", method, path),
+                                query,
+                                body: String::from_utf8_lossy(&body).into_owned(),
+                            });
+
+                            let (status, json) = respond(&responses, &sync, &method, &path);
+                            let mut response = Response::new(Body::from(json.to_string()));
+                            *response.status_mut() = status;
+                            Ok::<_, Infallible>(response)
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        FakeHomeserver {
+            url,
+            joined_rooms,
+            requests,
+        }
+    }
+
+    /// Gets the URL to reach the homeserver at.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Builds a client logged into this homeserver as `@frogbot:example.com`, synced so it knows
+    /// which rooms it's in.
+    pub async fn client(&self) -> Client {
+        let client = Client::builder()
+            .homeserver_url(&self.url)
+            .build()
+            .await
+            .unwrap();
+        client
+            .restore_session(Session {
+                access_token: "token".to_string(),
+                refresh_token: None,
+                user_id: UserId::parse("@frogbot:example.com").unwrap(),
+                device_id: "CURRENT".into(),
+            })
+            .await
+            .unwrap();
+        if !self.joined_rooms.is_empty() {
+            client.sync_once(SyncSettings::default()).await.unwrap();
+        }
+        client
+    }
+
+    /// Gets the requests with `method` whose path contains `path_part`.
+    pub fn requests(&self, method: &str, path_part: &str) -> Vec<ReceivedRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|request| {
+                let (request_method, path) =
+                    request.method_and_path.split_once(' ').unwrap_or_default();
+                request_method == method && path.contains(path_part)
+            })
+            .map(|request| ReceivedRequest {
+                method_and_path: request.method_and_path.clone(),
+                query: request.query.clone(),
+                body: request.body.clone(),
+            })
+            .collect()
+    }
+
+    /// Checks if it got a request with `method` whose path contains `path_part`.
+    pub fn got(&self, method: &str, path_part: &str) -> bool {
+        !self.requests(method, path_part).is_empty()
+    }
+
+    /// Gets the bodies of the messages sent to rooms, in order.
+    pub fn sent_messages(&self) -> Vec<serde_json::Value> {
+        self.requests("PUT", "/send/m.room.message/")
+            .iter()
+            .map(|request| serde_json::from_str(&request.body).unwrap())
+            .collect()
+    }
+}
+
+/// Works out what the [`FakeHomeserver`] answers a request with.
+fn respond(
+    responses: &[(&'static str, &'static str, serde_json::Value)],
+    sync: &serde_json::Value,
+    method: &str,
+    path: &str,
+) -> (StatusCode, serde_json::Value) {
+    if let Some((_, _, json)) = responses
+        .iter()
+        .find(|(m, part, _)| *m == method && path.contains(part))
+    {
+        let status = match json["errcode"].as_str() {
+            None => StatusCode::OK,
+            Some("M_FORBIDDEN") => StatusCode::FORBIDDEN,
+            Some("M_NOT_FOUND") => StatusCode::NOT_FOUND,
+            Some("M_LIMIT_EXCEEDED") => StatusCode::TOO_MANY_REQUESTS,
+            Some(_) => StatusCode::BAD_REQUEST,
+        };
+        return (status, json.clone());
+    }
+
+    let json = if path.ends_with("/versions") {
+        serde_json::json!({ "versions": ["v1.1", "v1.2", "v1.3"] })
+    } else if path.ends_with("/sync") {
+        sync.clone()
+    } else if path.contains("/keys/upload") {
+        serde_json::json!({ "one_time_key_counts": {} })
+    } else if path.contains("/keys/query") {
+        serde_json::json!({ "device_keys": {} })
+    } else if path.contains("/state/m.room.encryption") {
+        // None of the fake rooms are encrypted
+        return (
+            StatusCode::NOT_FOUND,
+            serde_json::json!({ "errcode": "M_NOT_FOUND", "error": "Event not found" }),
+        );
+    } else if path.contains("/send/") {
+        serde_json::json!({ "event_id": "$sent:example.com" })
+    } else {
+        serde_json::json!({})
+    };
+    (StatusCode::OK, json)
+}
+
+/// Serves `respond` on a free local port, returning the URL to reach it at.
+pub async fn serve<F, Fut>(respond: F) -> String
+where
+    F: Fn(Request<Body>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_service = make_service_fn(move |_| {
+        let respond = respond.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = respond(request);
+                async move { Ok::<_, Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("http://{}/", server.local_addr());
+    tokio::spawn(server);
+    url
+}
+
+/// A client for talking to [`serve`], which ignores any proxies set in the environment.
+pub fn local_client() -> reqwest::Client {
+    reqwest::Client::builder().no_proxy().build().unwrap()
+}
+
+/// A [`MetadataFetcher`] that hands out canned pages and API responses instead of going out to
+/// the network.
+#[derive(Default)]
+pub struct FakeFetcher {
+    /// The HTML of each page, by URL
+    pub pages: HashMap<String, String>,
+    /// The body of each API response, by the endpoint's URL without its query
+    pub api_responses: HashMap<String, String>,
+    /// Each PNG image, by URL
+    pub images: HashMap<String, Vec<u8>>,
+    /// How long each page takes to fetch
+    pub delay: Duration,
+    /// The pages, images and API endpoints that were fetched, in order
+    pub fetched: Mutex<Vec<String>>,
+    /// How many pages are being fetched right now, and the most there have been at once
+    pub in_flight: Mutex<(usize, usize)>,
+}
+
+impl MetadataFetcher for FakeFetcher {
+    fn fetch<'a>(&'a self, url: &'a str) -> FetchFuture<'a> {
+        Box::pin(async move {
+            self.fetched.lock().unwrap().push(url.to_string());
+            {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                in_flight.0 += 1;
+                in_flight.1 = in_flight.1.max(in_flight.0);
+            }
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.lock().unwrap().0 -= 1;
+
+            let html = self
+                .pages
+                .get(url)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no page at {}", url))?;
+            Ok::<_, anyhow::Error>(Page {
+                url: url.to_string(),
+                html,
+            })
+        })
+    }
+
+    fn fetch_image<'a>(&'a self, url: &'a str) -> ImageFuture<'a> {
+        self.fetched.lock().unwrap().push(url.to_string());
+        let image = match self.images.get(url) {
+            Some(data) => Ok(Image {
+                data: data.clone(),
+                content_type: mime::IMAGE_PNG,
+            }),
+            None => Err(anyhow::anyhow!("no image at {}", url)),
+        };
+        Box::pin(async move { image })
+    }
+
+    fn fetch_api(&self, request: ApiRequest) -> ApiFuture<'_> {
+        let endpoint = request.url.as_str().split('?').next().unwrap_or_default();
+        self.fetched.lock().unwrap().push(endpoint.to_string());
+        let response = self
+            .api_responses
+            .get(endpoint)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("no API response for {}", endpoint));
+        Box::pin(async move { response })
+    }
+}
+
+/// Builds a message event in `!room:example.com` with `content`, sent by `@frog:example.com`.
+pub fn message_event(content: serde_json::Value) -> OriginalRoomMessageEvent {
+    serde_json::from_value(serde_json::json!({
+        "type": "m.room.message",
+        "event_id": "$message:example.com",
+        "room_id": "!room:example.com",
+        "sender": "@frog:example.com",
+        "origin_server_ts": 1_700_000_000_000u64,
+        "content": content,
+    }))
+    .unwrap()
+}
+
+/// Builds the state frogbot's event handlers get, with `config` and `fetcher` and nothing else
+/// set.
+pub fn bot_state(config: Config, fetcher: impl MetadataFetcher + 'static) -> BotState {
+    BotState {
+        config: LiveConfig::new(Arc::new(config)),
+        embed_settings: Arc::default(),
+        embed_replies: Arc::default(),
+        fetcher: Arc::new(fetcher),
+        reqwest_client: reqwest::Client::new(),
+        status: Arc::new(Status::new()),
+    }
+}
+
+/// Makes an empty directory for a test to keep files in.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("frogbot-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A config with everything [`Config::validate`] checks filled in.
+pub fn valid_config() -> Config {
+    let mut config = Config::default();
+    config.homeserver = "https://matrix.example.com".to_string();
+    config.username = "frogbot".to_string();
+    config.display_name = "frogbot".to_string();
+    config.password = "hunter2".to_string();
+    config
+}
+
+/// A logger that keeps every message, so tests can check what level things get logged at.
+struct RecordingLogger(Mutex<Vec<(log::Level, String)>>);
+
+impl log::Log for RecordingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0
+            .lock()
+            .unwrap()
+            .push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Where log messages go once [`record_logs`] has been called.
+static LOGGER: RecordingLogger = RecordingLogger(Mutex::new(Vec::new()));
+
+/// Starts keeping every log message, if that hasn't been started already.
+pub fn record_logs() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+}
+
+/// Gets the levels of the log messages recorded so far that are exactly `message`.
+pub fn logged_levels(message: &str) -> Vec<log::Level> {
+    LOGGER
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, logged)| logged == message)
+        .map(|(level, _)| *level)
+        .collect()
+}
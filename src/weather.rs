@@ -0,0 +1,275 @@
+//! # The Weather Module
+//!
+//! This module looks up the current weather for `!weather`.
+
+use crate::cache::TtlCache;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use std::{future::Future, pin::Pin, sync::Mutex, time::Duration};
+
+/// How long the weather for a location is reused before it's looked up again.
+const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// The most locations kept in the cache at once.
+const MAX_CACHED_LOCATIONS: usize = 100;
+
+lazy_static! {
+    /// Recently looked up weather, by lowercased location, along with when it was looked up
+    static ref WEATHER_CACHE: Mutex<TtlCache<String, Weather>> =
+        Mutex::new(TtlCache::new(MAX_CACHED_LOCATIONS));
+}
+
+/// The weather services frogbot can get the weather from.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WeatherProviderKind {
+    /// [Open-Meteo](https://open-meteo.com/), which doesn't need an API key
+    #[default]
+    OpenMeteo,
+}
+
+/// The current weather somewhere.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Weather {
+    /// The name of the place the weather is for, as the provider found it (e.g. "Paris, France")
+    pub location: String,
+    /// The temperature in degrees Celsius
+    pub temperature: f64,
+    /// What the weather is like (e.g. "Light rain")
+    pub conditions: String,
+    /// The wind speed in km/h
+    pub wind_speed: f64,
+    /// The relative humidity in percent
+    pub humidity: f64,
+}
+
+impl Weather {
+    /// Formats the weather as a reply for `!weather`.
+    pub fn to_reply(&self) -> String {
+        format!(
+            "🌡️ {}: {:.1}°C, {} · 💨 {:.0} km/h · 💧 {:.0}%",
+            self.location, self.temperature, self.conditions, self.wind_speed, self.humidity
+        )
+    }
+}
+
+/// The future returned by [`WeatherProvider::current_weather`].
+pub type WeatherFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<Option<Weather>>> + Send + 'a>>;
+
+/// Looks up the current weather.
+///
+/// `!weather` only gets the weather through this, so it can be given one that returns canned
+/// weather instead of going out to the network, or a different weather service.
+pub trait WeatherProvider: Send + Sync {
+    /// Gets the current weather at `location`, or `None` if the location couldn't be found.
+    fn current_weather<'a>(&'a self, location: &'a str) -> WeatherFuture<'a>;
+}
+
+/// Builds the [`WeatherProvider`] for the configured weather service.
+pub fn provider(kind: WeatherProviderKind, client: reqwest::Client) -> Box<dyn WeatherProvider> {
+    match kind {
+        WeatherProviderKind::OpenMeteo => Box::new(OpenMeteo::new(client)),
+    }
+}
+
+/// Gets the current weather at `location`, reusing what was looked up in the last few minutes.
+pub async fn current_weather(
+    provider: &dyn WeatherProvider,
+    location: &str,
+) -> anyhow::Result<Option<Weather>> {
+    let key = location.to_lowercase();
+    if let Some(weather) = WEATHER_CACHE.lock().unwrap().get(&key, CACHE_TTL) {
+        return Ok(Some(weather.clone()));
+    }
+
+    let weather = provider.current_weather(location).await?;
+    if let Some(weather) = &weather {
+        WEATHER_CACHE
+            .lock()
+            .unwrap()
+            .insert(key, weather.clone(), CACHE_TTL);
+    }
+    Ok(weather)
+}
+
+/// Gets the weather from [Open-Meteo](https://open-meteo.com/).
+pub struct OpenMeteo {
+    client: reqwest::Client,
+}
+
+impl OpenMeteo {
+    /// Creates a provider that makes its requests with `client`.
+    pub fn new(client: reqwest::Client) -> Self {
+        OpenMeteo { client }
+    }
+
+    async fn fetch(&self, location: &str) -> anyhow::Result<Option<Weather>> {
+        let geocoding: OpenMeteoGeocoding = self
+            .client
+            .get("https://geocoding-api.open-meteo.com/v1/search")
+            .query(&[("name", location), ("count", "1")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let Some(place) = geocoding.results.into_iter().next() else {
+            return Ok(None);
+        };
+
+        let forecast: OpenMeteoForecast = self
+            .client
+            .get("https://api.open-meteo.com/v1/forecast")
+            .query(&[
+                ("latitude", place.latitude.to_string()),
+                ("longitude", place.longitude.to_string()),
+                (
+                    "current",
+                    "temperature_2m,relative_humidity_2m,weather_code,wind_speed_10m".to_string(),
+                ),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let location = match place.country {
+            Some(country) => format!("{}, {}", place.name, country),
+            None => place.name,
+        };
+        Ok(Some(Weather {
+            location,
+            temperature: forecast.current.temperature_2m,
+            conditions: weather_code_description(forecast.current.weather_code).to_string(),
+            wind_speed: forecast.current.wind_speed_10m,
+            humidity: forecast.current.relative_humidity_2m,
+        }))
+    }
+}
+
+impl WeatherProvider for OpenMeteo {
+    fn current_weather<'a>(&'a self, location: &'a str) -> WeatherFuture<'a> {
+        Box::pin(self.fetch(location))
+    }
+}
+
+/// The parts of an Open-Meteo geocoding response frogbot uses.
+#[derive(Deserialize)]
+struct OpenMeteoGeocoding {
+    // Left out entirely when nothing matches
+    #[serde(default)]
+    results: Vec<OpenMeteoPlace>,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoPlace {
+    name: String,
+    country: Option<String>,
+    latitude: f64,
+    longitude: f64,
+}
+
+/// The parts of an Open-Meteo forecast response frogbot uses.
+#[derive(Deserialize)]
+struct OpenMeteoForecast {
+    current: OpenMeteoCurrent,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoCurrent {
+    temperature_2m: f64,
+    relative_humidity_2m: f64,
+    weather_code: u8,
+    wind_speed_10m: f64,
+}
+
+/// Describes a [WMO weather code](https://open-meteo.com/en/docs#weathervariables).
+fn weather_code_description(code: u8) -> &'static str {
+    match code {
+        0 => "Clear sky",
+        1 => "Mainly clear",
+        2 => "Partly cloudy",
+        3 => "Overcast",
+        45 | 48 => "Fog",
+        51 | 53 | 55 => "Drizzle",
+        56 | 57 => "Freezing drizzle",
+        61 => "Light rain",
+        63 => "Rain",
+        65 => "Heavy rain",
+        66 | 67 => "Freezing rain",
+        71 => "Light snow",
+        73 => "Snow",
+        75 => "Heavy snow",
+        77 => "Snow grains",
+        80..=82 => "Rain showers",
+        85 | 86 => "Snow showers",
+        95 => "Thunderstorm",
+        96 | 99 => "Thunderstorm with hail",
+        _ => "Unknown conditions",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A [`WeatherProvider`] that always has the same weather, and counts how often it's asked.
+    #[derive(Default)]
+    struct StubProvider {
+        lookups: Mutex<Vec<String>>,
+    }
+
+    impl WeatherProvider for StubProvider {
+        fn current_weather<'a>(&'a self, location: &'a str) -> WeatherFuture<'a> {
+            self.lookups.lock().unwrap().push(location.to_string());
+            let weather = (location != "Atlantis").then(|| Weather {
+                location: format!("{}, Frogland", location),
+                temperature: 21.44,
+                conditions: "Light rain".to_string(),
+                wind_speed: 12.3,
+                humidity: 80.0,
+            });
+            Box::pin(async move { Ok(weather) })
+        }
+    }
+
+    #[tokio::test]
+    async fn weather_comes_from_the_provider() {
+        let provider = StubProvider::default();
+        let weather = current_weather(&provider, "Pondville")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            weather.to_reply(),
+            "🌡️ Pondville, Frogland: 21.4°C, Light rain · 💨 12 km/h · 💧 80%"
+        );
+    }
+
+    #[tokio::test]
+    async fn weather_is_cached_per_location() {
+        let provider = StubProvider::default();
+        let first = current_weather(&provider, "Lilypad").await.unwrap();
+        let second = current_weather(&provider, "LILYPAD").await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(*provider.lookups.lock().unwrap(), ["Lilypad"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_locations_are_not_cached() {
+        let provider = StubProvider::default();
+        assert_eq!(current_weather(&provider, "Atlantis").await.unwrap(), None);
+        assert_eq!(current_weather(&provider, "Atlantis").await.unwrap(), None);
+        assert_eq!(provider.lookups.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn weather_codes_are_described() {
+        assert_eq!(weather_code_description(0), "Clear sky");
+        assert_eq!(weather_code_description(81), "Rain showers");
+        assert_eq!(weather_code_description(200), "Unknown conditions");
+    }
+}
@@ -0,0 +1,145 @@
+//! # The Wiki Module
+//!
+//! This module looks up Wikipedia articles for `!wiki`.
+
+use crate::embeds::Embed;
+use serde::Deserialize;
+use url::Url;
+
+/// The Wikipedia REST API endpoint that article summaries are fetched from.
+const SUMMARY_API_URL: &str = "https://en.wikipedia.org/api/rest_v1/page/summary/";
+
+/// What Wikipedia has for a query.
+pub enum WikiSummary {
+    /// An article, as an embed linking to it
+    Article(Embed),
+    /// A disambiguation page, listing articles the query could mean
+    Disambiguation {
+        /// The title of the disambiguation page
+        title: String,
+        /// The URL of the disambiguation page
+        url: String,
+    },
+    /// There's no article with that title
+    NotFound,
+}
+
+/// The parts of a Wikipedia page summary frogbot uses.
+#[derive(Deserialize)]
+struct SummaryResponse {
+    #[serde(rename = "type")]
+    kind: String,
+    title: String,
+    #[serde(default)]
+    extract: String,
+    thumbnail: Option<Thumbnail>,
+    content_urls: ContentUrls,
+}
+
+#[derive(Deserialize)]
+struct Thumbnail {
+    source: String,
+}
+
+#[derive(Deserialize)]
+struct ContentUrls {
+    desktop: PageUrls,
+}
+
+#[derive(Deserialize)]
+struct PageUrls {
+    page: String,
+}
+
+/// Gets the summary of the Wikipedia article titled `query`, following redirects.
+pub async fn fetch_summary(
+    reqwest_client: &reqwest::Client,
+    query: &str,
+) -> anyhow::Result<WikiSummary> {
+    let mut url = Url::parse(SUMMARY_API_URL)?;
+    // Article titles use underscores for spaces, and pushing the segment percent-encodes the rest
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Wikipedia API URL can't have a path"))?
+        .pop_if_empty()
+        .push(&query.trim().replace(' ', "_"));
+    url.query_pairs_mut().append_pair("redirect", "true");
+
+    let response = reqwest_client.get(url).send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(WikiSummary::NotFound);
+    }
+    let summary: SummaryResponse = response.error_for_status()?.json().await?;
+    Ok(summary_from_response(summary))
+}
+
+/// Turns a summary from the API into what frogbot replies with.
+fn summary_from_response(summary: SummaryResponse) -> WikiSummary {
+    let url = summary.content_urls.desktop.page;
+    if summary.kind == "disambiguation" {
+        return WikiSummary::Disambiguation {
+            title: summary.title,
+            url,
+        };
+    }
+
+    let mut embed = Embed::new(summary.title, summary.extract);
+    embed.image = summary.thumbnail.map(|thumbnail| thumbnail.source);
+    embed.canonical_url = Some(url.clone());
+    embed.link = Some(url);
+    WikiSummary::Article(embed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::message_event, Config};
+
+    /// Builds a summary response like the Wikipedia API's, of the `kind` given.
+    fn summary_response(kind: &str) -> SummaryResponse {
+        serde_json::from_value(serde_json::json!({
+            "type": kind,
+            "title": "Frog",
+            "extract": "A frog is any member of a diverse group of tailless amphibians.",
+            "thumbnail": { "source": "https://upload.wikimedia.org/frog.jpg" },
+            "content_urls": {
+                "desktop": { "page": "https://en.wikipedia.org/wiki/Frog" },
+                "mobile": { "page": "https://en.m.wikipedia.org/wiki/Frog" },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn articles_reply_with_the_extract() {
+        let WikiSummary::Article(embed) = summary_from_response(summary_response("standard"))
+        else {
+            panic!("summary isn't an article");
+        };
+        assert_eq!(embed.title, "Frog");
+        assert_eq!(
+            embed.image.as_deref(),
+            Some("https://upload.wikimedia.org/frog.jpg")
+        );
+        assert_eq!(
+            embed.link.as_deref(),
+            Some("https://en.wikipedia.org/wiki/Frog")
+        );
+
+        let event = message_event(serde_json::json!({ "msgtype": "m.text", "body": "!wiki frog" }));
+        let content = embed.to_message_content(&Config::default(), &event);
+        let content = serde_json::to_value(&content).unwrap();
+        assert!(content["body"]
+            .as_str()
+            .unwrap()
+            .contains("A frog is any member of a diverse group of tailless amphibians."));
+    }
+
+    #[test]
+    fn disambiguation_pages_are_not_articles() {
+        assert!(matches!(
+            summary_from_response(summary_response("disambiguation")),
+            WikiSummary::Disambiguation { title, url }
+                if title == "Frog" && url == "https://en.wikipedia.org/wiki/Frog"
+        ));
+    }
+}